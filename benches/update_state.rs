@@ -0,0 +1,41 @@
+use active_rdc_webhook_notifier::ClientStateMap;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rdc_connections::{ClientInfo, RemoteDesktopSessionInfo, RemoteDesktopSessionState};
+
+/// active sessions of `count` clients, leaving out every seventh one starting at `skip`
+fn sessions(count: usize, skip: usize) -> Vec<RemoteDesktopSessionInfo> {
+    (0..count)
+        .filter(|c| c % 7 != skip)
+        .map(|c| RemoteDesktopSessionInfo {
+            session_id: 1,
+            state: RemoteDesktopSessionState::Active,
+            client_info: ClientInfo {
+                user: "alice".to_owned(),
+                client: format!("CLIENT{:05}", c),
+                address: (0, [0; 31]),
+            },
+        })
+        .collect()
+}
+
+/// a poll which drops a seventh of the clients followed by one bringing them back, so every
+/// iteration reports as many disconnects as connects
+fn update_state(c: &mut Criterion) {
+    let mut group = c.benchmark_group("update_state");
+    for count in [1000, 4000, 16000] {
+        let (all, fewer) = (sessions(count, 7), sessions(count, 3));
+        group.throughput(Throughput::Elements(2 * count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            let mut map = ClientStateMap::new();
+            map.update_state(&all);
+            b.iter(|| {
+                map.update_state(&fewer);
+                map.update_state(&all)
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, update_state);
+criterion_main!(benches);
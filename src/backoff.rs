@@ -0,0 +1,75 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// exponential backoff, `base` first and `multiplier` times the previous delay after that, up
+/// to `max`; with full jitter each delay is picked at random between zero and that
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    multiplier: f64,
+    max: Duration,
+    jitter: bool,
+    /// delays handed out since the last reset
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, multiplier: f64, max: Duration) -> Self {
+        Self {
+            base,
+            multiplier,
+            max,
+            jitter: false,
+            attempts: 0,
+        }
+    }
+
+    /// spreads out retries of callers which failed at the same time
+    pub fn full_jitter(mut self) -> Self {
+        self.jitter = true;
+        self
+    }
+
+    /// the delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let exponent = i32::try_from(self.attempts).unwrap_or(i32::MAX);
+        let secs =
+            (self.base.as_secs_f64() * self.multiplier.powi(exponent)).min(self.max.as_secs_f64());
+        let delay = Duration::try_from_secs_f64(secs).unwrap_or(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+        if self.jitter {
+            delay.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+        } else {
+            delay
+        }
+    }
+
+    /// starts over from `base`, after a success
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_up_to_its_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), 2.0, Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        let mut jittered =
+            Backoff::new(Duration::from_secs(1), 2.0, Duration::from_secs(5)).full_jitter();
+        for max in [1, 2, 4, 5, 5] {
+            assert!(jittered.next_delay() <= Duration::from_secs(max));
+        }
+        let mut huge = Backoff::new(Duration::from_secs(1), 10.0, Duration::MAX);
+        for _ in 0..100 {
+            huge.next_delay();
+        }
+        assert_eq!(huge.next_delay(), Duration::MAX);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{client_key, config::parse_duration};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use log::{error, info, warn};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// client name as given and when its mute ends, never when `None`
+type Mute = (String, Option<DateTime<Local>>);
+
+/// clients whose events are tracked but not posted, keyed by `client_key`
+#[derive(Debug, Default)]
+pub struct Mutes {
+    clients: Mutex<HashMap<String, Mute>>,
+}
+
+impl Mutes {
+    pub fn mute(&self, client: &str, until: Option<DateTime<Local>>) {
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(client_key(client), (client.to_owned(), until));
+    }
+
+    /// returns false when the client was not muted
+    pub fn unmute(&self, client: &str) -> bool {
+        self.clients
+            .lock()
+            .unwrap()
+            .remove(&client_key(client))
+            .is_some()
+    }
+
+    pub fn is_muted(&self, client: &str) -> bool {
+        self.expire();
+        self.clients
+            .lock()
+            .unwrap()
+            .contains_key(&client_key(client))
+    }
+
+    pub fn list(&self) -> Vec<Mute> {
+        self.expire();
+        let mut muted: Vec<_> = self.clients.lock().unwrap().values().cloned().collect();
+        muted.sort();
+        muted
+    }
+
+    fn expire(&self) {
+        let now = Local::now();
+        self.clients.lock().unwrap().retain(|_, (client, until)| {
+            let expired = until.is_some_and(|until| until <= now);
+            if expired {
+                info!("mute of '{}' is over", client);
+            }
+            !expired
+        });
+    }
+}
+
+fn mute(mutes: &Mutes, client: &str, until: Option<DateTime<Local>>) -> String {
+    mutes.mute(client, until);
+    let until = match until {
+        Some(until) => until.format("%F %T").to_string(),
+        None => "unmuted".to_owned(),
+    };
+    info!("'{}' muted until {}", client, until);
+    format!("muted '{}' until {}", client, until)
+}
+
+/// answers a single line of `mute <client> [duration]`, `unmute <client>` or `list-muted`
+pub fn handle_command(mutes: &Mutes, line: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["mute", client] => mute(mutes, client, None),
+        ["mute", client, duration] => match parse_duration(duration) {
+            // durations beyond what a date can hold are as good as forever
+            Ok(duration) => mute(
+                mutes,
+                client,
+                chrono::Duration::from_std(duration)
+                    .ok()
+                    .and_then(|d| Local::now().checked_add_signed(d)),
+            ),
+            Err(e) => format!("duration '{}' is invalid. {}", duration, e),
+        },
+        ["unmute", client] if mutes.unmute(client) => {
+            info!("'{}' unmuted", client);
+            format!("unmuted '{}'", client)
+        }
+        ["unmute", client] => format!("'{}' is not muted", client),
+        ["list-muted"] => {
+            let muted = mutes.list();
+            if muted.is_empty() {
+                return "no client is muted".to_owned();
+            }
+            muted
+                .iter()
+                .map(|(client, until)| match until {
+                    Some(until) => format!("'{}' until {}", client, until.format("%F %T")),
+                    None => format!("'{}' until unmuted", client),
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        _ => format!(
+            "unknown command '{}', try 'mute <client> [duration]', 'unmute <client>' or 'list-muted'",
+            line.trim()
+        ),
+    }
+}
+
+async fn serve_connection(mutes: &Mutes, stream: TcpStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut reply = handle_command(mutes, &line);
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// takes line commands on `addr`, which is meant to be a loopback address as nothing is
+/// authenticated
+pub async fn spawn_control_server(addr: SocketAddr, mutes: Arc<Mutes>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow!("control server could not bind to {}. {:?}", addr, e))?;
+    info!("taking mute commands on {}", addr);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let mutes = mutes.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_connection(&mutes, stream).await {
+                            warn!("control connection from {} failed. {:?}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => error!("control connection could not be accepted. {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_commands_mute_and_unmute() {
+        let mutes = Mutes::default();
+        assert!(handle_command(&mutes, "mute WS01 30m").starts_with("muted 'WS01' until "));
+        assert!(mutes.is_muted("ws01"));
+        assert!(handle_command(&mutes, "list-muted").starts_with("'WS01' until "));
+        assert_eq!(handle_command(&mutes, "unmute ws01"), "unmuted 'ws01'");
+        assert!(!mutes.is_muted("WS01"));
+        assert_eq!(handle_command(&mutes, "unmute WS01"), "'WS01' is not muted");
+        assert_eq!(handle_command(&mutes, "list-muted"), "no client is muted");
+        mutes.mute("WS02", Some(Local::now()));
+        assert!(!mutes.is_muted("WS02"));
+    }
+}
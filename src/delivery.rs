@@ -0,0 +1,528 @@
+use crate::{
+    format::{plural, Event},
+    metrics,
+    webhook::{mask_url, WebhookClient, WebhookRouter},
+    MsgSender,
+};
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+    time::Duration,
+};
+
+/// what to do with events polled while the delivery queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueFull {
+    /// hold up polling until the worker catches up
+    #[default]
+    Wait,
+    Drop,
+}
+
+impl FromStr for QueueFull {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wait" => Ok(Self::Wait),
+            "drop" => Ok(Self::Drop),
+            _ => Err(anyhow!("unknown queue full policy '{}'", s)),
+        }
+    }
+}
+
+/// what to do with deliveries the webhook still refuses after every retry
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// discarded without a word, only counted in metrics
+    Drop,
+    Log,
+    /// logged and kept in the outbox, to be posted again once the webhook takes posts again, or
+    /// in the dead-letter file without an outbox
+    Persist,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "log" => Ok(Self::Log),
+            "persist" => Ok(Self::Persist),
+            _ => Err(anyhow!("unknown webhook failure policy '{}'", s)),
+        }
+    }
+}
+
+/// events of a poll on their way to the webhook
+#[derive(Serialize, Deserialize)]
+pub struct Delivery {
+    /// the first server picks the webhook, batches only group servers sharing one
+    pub servers: Vec<String>,
+    pub events: Vec<Event>,
+    pub batch: bool,
+    /// high severity events, which may have a webhook of their own
+    #[serde(default)]
+    pub alert: bool,
+}
+
+impl Delivery {
+    /// events of a server no webhook is configured for anymore, e.g. restored from the outbox
+    /// after it was removed, are dropped rather than kept for a post which never succeeds
+    async fn post(&mut self, router: &MsgSender) -> Result<()> {
+        match router.for_event(&self.servers[0], self.alert) {
+            Some(client) => self.post_to(client).await,
+            None => {
+                warn!(
+                    "'{}' has no webhook anymore, dropping {}",
+                    self.servers[0],
+                    plural(self.events.len(), "event", "events")
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// events posted one by one are taken out once delivered, so a delivery kept after a
+    /// failure only holds those which were not
+    async fn post_to(&mut self, client: &WebhookClient) -> Result<()> {
+        if self.batch {
+            return client.post_batch(&self.events).await;
+        }
+        while let Some(event) = self.events.first() {
+            client.post(event).await?;
+            self.events.remove(0);
+        }
+        Ok(())
+    }
+}
+
+/// a delivery given up on, a line of the dead-letter file
+#[derive(Serialize, Deserialize)]
+struct DeadLetter {
+    /// the webhook it was meant for, events keep the time they were detected at
+    url: String,
+    #[serde(flatten)]
+    delivery: Delivery,
+}
+
+/// deliveries given up on, appended as json lines to be posted again by `replay-deadletter`
+pub struct DeadLetters {
+    path: Option<PathBuf>,
+}
+
+impl DeadLetters {
+    /// without a path deliveries given up on are lost
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path }
+    }
+
+    fn append(&self, router: &MsgSender, delivery: Delivery) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let events = delivery.events.len();
+        let letter = DeadLetter {
+            url: router
+                .url_for_event(&delivery.servers[0], delivery.alert)
+                .to_owned(),
+            delivery,
+        };
+        let result = serde_json::to_string(&letter)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", line)?;
+                Ok(())
+            });
+        match result {
+            Ok(()) => warn!(
+                "{} written to dead-letter file {:?}",
+                plural(events, "undelivered event", "undelivered events"),
+                path
+            ),
+            Err(e) => error!("dead-letter file {:?} could not be written. {:?}", path, e),
+        }
+    }
+}
+
+/// posts every dead letter again to the webhook it was meant for, keeping those which fail or
+/// whose webhook is not configured anymore; returns how many were delivered and how many are
+/// left. The file is rewritten, so it is meant to be run while the notifier is not adding to it
+pub async fn replay_dead_letters(path: &Path, router: &WebhookRouter) -> Result<(usize, usize)> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow!("dead-letter file {:?} could not be read. {:?}", path, e))?;
+    let mut delivered = 0;
+    let mut left = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut letter: DeadLetter = match serde_json::from_str(line) {
+            Ok(letter) => letter,
+            Err(e) => {
+                error!(
+                    "line {} of dead-letter file {:?} is invalid, keeping it. {}",
+                    number + 1,
+                    path,
+                    e
+                );
+                left.push(line.to_owned());
+                continue;
+            }
+        };
+        let result = match router.client_for(&letter.url) {
+            Some(client) => letter.delivery.post_to(client).await,
+            None => Err(anyhow!(
+                "webhook {} is not configured anymore",
+                mask_url(&letter.url)
+            )),
+        };
+        match result {
+            Ok(()) => delivered += 1,
+            Err(e) => {
+                error!("dead letter could not be delivered, keeping it. {:?}", e);
+                // without the events which did get delivered
+                left.push(serde_json::to_string(&letter)?);
+            }
+        }
+    }
+    // write aside and rename, like the outbox
+    let tmp_path = path.with_extension("tmp");
+    fs::write(
+        &tmp_path,
+        left.iter()
+            .map(|line| format!("{}\n", line))
+            .collect::<String>(),
+    )?;
+    fs::rename(&tmp_path, path)?;
+    Ok((delivered, left.len()))
+}
+
+/// deliveries the webhook did not take, kept on disk so they survive a restart
+pub struct Outbox {
+    path: Option<PathBuf>,
+    /// events older than this are given up on
+    ttl: Duration,
+    pending: Vec<Delivery>,
+    /// where deliveries go once given up on, or right away without an outbox
+    dead_letters: DeadLetters,
+}
+
+impl Outbox {
+    /// without a path nothing is kept, failed deliveries go to the dead letters, if any
+    pub fn load(path: Option<PathBuf>, ttl: Duration, dead_letters: DeadLetters) -> Self {
+        let pending = match &path {
+            Some(path) if path.exists() => fs::read_to_string(path)
+                .map_err(|e| anyhow!("outbox {:?} could not be read. {:?}", path, e))
+                .and_then(|content| {
+                    serde_json::from_str(&content)
+                        .map_err(|e| anyhow!("outbox {:?} is invalid. {}", path, e))
+                })
+                .unwrap_or_else(|e| {
+                    error!("{:?}", e);
+                    Vec::new()
+                }),
+            _ => Vec::new(),
+        };
+        // expired by the first retry, which knows where they were meant to go
+        Self {
+            path,
+            ttl,
+            pending,
+            dead_letters,
+        }
+    }
+
+    fn expire(&mut self, router: &MsgSender) {
+        let now = Local::now();
+        let ttl = self.ttl;
+        let mut expired = 0;
+        for delivery in &mut self.pending {
+            let (events, old): (Vec<Event>, Vec<Event>) = std::mem::take(&mut delivery.events)
+                .into_iter()
+                .partition(|e| (now - e.at).to_std().unwrap_or_default() < ttl);
+            delivery.events = events;
+            if old.is_empty() {
+                continue;
+            }
+            expired += old.len();
+            self.dead_letters.append(
+                router,
+                Delivery {
+                    servers: delivery.servers.clone(),
+                    events: old,
+                    batch: delivery.batch,
+                    alert: delivery.alert,
+                },
+            );
+        }
+        self.pending.retain(|d| !d.events.is_empty());
+        if expired > 0 {
+            warn!(
+                "{} expired",
+                plural(expired, "undelivered event", "undelivered events")
+            );
+        }
+    }
+
+    pub fn keep(&mut self, router: &MsgSender, delivery: Delivery) {
+        if self.path.is_some() {
+            self.pending.push(delivery);
+        } else {
+            self.dead_letters.append(router, delivery);
+        }
+    }
+
+    /// posts what is pending, keeping whatever still fails
+    async fn retry(&mut self, router: &MsgSender) {
+        self.expire(router);
+        if self.pending.is_empty() {
+            return;
+        }
+        info!("retrying {} undelivered posts", self.pending.len());
+        for mut delivery in std::mem::take(&mut self.pending) {
+            if let Err(e) = delivery.post(router).await {
+                error!("undelivered post failed again. {:?}", e);
+                self.pending.push(delivery);
+            }
+        }
+        self.save();
+    }
+
+    /// write aside and rename, like the state file
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let result = serde_json::to_string(&self.pending)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| {
+                let tmp_path = path.with_extension("tmp");
+                fs::write(&tmp_path, content)?;
+                fs::rename(&tmp_path, path)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            error!("outbox {:?} could not be written. {:?}", path, e);
+        }
+    }
+}
+
+struct Queued {
+    /// the router current when the events were polled, the configuration may be reloaded since
+    router: MsgSender,
+    delivery: Delivery,
+}
+
+#[derive(Clone)]
+pub struct DeliveryQueue {
+    tx: mpsc::Sender<Queued>,
+    when_full: QueueFull,
+}
+
+impl DeliveryQueue {
+    pub async fn push(&self, router: MsgSender, delivery: Delivery) {
+        let queued = match self.tx.try_send(Queued { router, delivery }) {
+            Ok(()) => return,
+            Err(TrySendError::Full(queued)) => queued,
+            Err(TrySendError::Closed(_)) => {
+                error!("delivery worker is gone, events are lost");
+                return;
+            }
+        };
+        match self.when_full {
+            QueueFull::Wait => {
+                warn!("delivery queue is full, waiting for the webhook to catch up");
+                if self.tx.send(queued).await.is_err() {
+                    error!("delivery worker is gone, events are lost");
+                }
+            }
+            QueueFull::Drop => {
+                warn!(
+                    "delivery queue is full, dropping {}",
+                    plural(queued.delivery.events.len(), "event", "events")
+                );
+            }
+        }
+    }
+}
+
+/// the worker first retries what the outbox holds, then posts until every `DeliveryQueue` is
+/// dropped and the queue is drained; failed deliveries are handled by `on_failure`, those
+/// persisted are retried once a post succeeds again
+pub fn spawn_delivery_worker(
+    capacity: usize,
+    when_full: QueueFull,
+    on_failure: FailurePolicy,
+    router: MsgSender,
+    mut outbox: Outbox,
+) -> (DeliveryQueue, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::channel::<Queued>(capacity);
+    let worker = tokio::spawn(async move {
+        outbox.retry(&router).await;
+        while let Some(Queued {
+            router,
+            mut delivery,
+        }) = rx.recv().await
+        {
+            match delivery.post(&router).await {
+                Ok(()) => outbox.retry(&router).await,
+                Err(e) => {
+                    for server in &delivery.servers {
+                        metrics::WEBHOOK_FAILURES.with_label_values(&[server]).inc();
+                    }
+                    match on_failure {
+                        FailurePolicy::Drop => {}
+                        FailurePolicy::Log => error!("{:?}", e),
+                        FailurePolicy::Persist => {
+                            error!("{:?}", e);
+                            outbox.keep(&router, delivery);
+                            outbox.save();
+                        }
+                    }
+                }
+            }
+        }
+    });
+    (DeliveryQueue { tx, when_full }, worker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ServerConfig, UserInput},
+        format::EventKind,
+        webhook::WebhookRouter,
+    };
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server, StatusCode,
+    };
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    /// a webhook taking the first `accepted` posts and rejecting the rest, returns its url
+    fn webhook(accepted: usize) -> String {
+        let posts = Arc::new(AtomicUsize::new(0));
+        let make_svc = make_service_fn(move |_| {
+            let posts = posts.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let status = if posts.fetch_add(1, Ordering::SeqCst) < accepted {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::BAD_REQUEST
+                    };
+                    async move { Response::builder().status(status).body(Body::empty()) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn delivered_events_are_not_kept_after_a_failure() {
+        let input = UserInput {
+            url: webhook(1),
+            ..UserInput::default()
+        };
+        let router: MsgSender = Arc::new(WebhookRouter::new(&input).unwrap());
+        let mut delivery = Delivery {
+            servers: vec!["SERVER01".to_owned()],
+            events: ["first", "second", "third"]
+                .iter()
+                .map(|text| Event::new(EventKind::Connected, *text))
+                .collect(),
+            batch: false,
+            alert: false,
+        };
+        assert!(delivery.post(&router).await.is_err());
+        let left: Vec<&str> = delivery.events.iter().map(|e| e.text.as_str()).collect();
+        assert_eq!(left, vec!["second", "third"]);
+    }
+
+    #[tokio::test]
+    async fn deliveries_without_a_webhook_are_dropped() {
+        let input = UserInput {
+            servers: vec![ServerConfig {
+                name: "SERVER01".to_owned(),
+                period: None,
+                url: Some("https://example.com/hook".to_owned()),
+            }],
+            dry_run: true,
+            ..UserInput::default()
+        };
+        let router: MsgSender = Arc::new(WebhookRouter::new(&input).unwrap());
+        let delivery = |server: &str| Delivery {
+            servers: vec![server.to_owned()],
+            events: vec![Event::new(EventKind::Connected, "connected")],
+            batch: false,
+            alert: false,
+        };
+        let mut outbox = Outbox::load(None, Duration::ZERO, DeadLetters::new(None));
+        outbox.pending.push(delivery("SERVER02"));
+        let (queue, worker) = spawn_delivery_worker(
+            4,
+            QueueFull::Wait,
+            FailurePolicy::Persist,
+            router.clone(),
+            outbox,
+        );
+        queue.push(router.clone(), delivery("SERVER02")).await;
+        queue.push(router.clone(), delivery("SERVER01")).await;
+        drop(queue);
+        worker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_replayed_to_their_webhook() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead_letters.jsonl");
+        let mut input = UserInput {
+            url: "https://example.com/hook".to_owned(),
+            dry_run: true,
+            ..UserInput::default()
+        };
+        let router: MsgSender = Arc::new(WebhookRouter::new(&input).unwrap());
+        let mut outbox = Outbox::load(None, Duration::ZERO, DeadLetters::new(Some(path.clone())));
+        let delivery = |text: &str| Delivery {
+            servers: vec!["SERVER01".to_owned()],
+            events: vec![Event::new(EventKind::Connected, text)],
+            batch: false,
+            alert: false,
+        };
+        outbox.keep(&router, delivery("first"));
+        outbox.keep(&router, delivery("second"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        input.url = "https://example.com/other".to_owned();
+        let moved = WebhookRouter::new(&input).unwrap();
+        assert_eq!(replay_dead_letters(&path, &moved).await.unwrap(), (0, 2));
+        assert_eq!(replay_dead_letters(&path, &router).await.unwrap(), (2, 0));
+        assert!(std::fs::read_to_string(&path).unwrap().is_empty());
+    }
+}
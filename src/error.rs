@@ -0,0 +1,30 @@
+use reqwest::StatusCode;
+use thiserror::Error;
+
+/// failures callers branch on, anything else stays a plain `anyhow::Error`
+#[derive(Debug, Error)]
+pub enum NotifierError {
+    #[error("invalid configuration")]
+    Config(#[source] anyhow::Error),
+    #[error("querying '{server}' failed")]
+    ServerQuery {
+        server: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("webhook responded with {status}")]
+    Webhook { status: StatusCode },
+}
+
+impl NotifierError {
+    /// whether trying again later may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Config(_) => false,
+            Self::ServerQuery { .. } => true,
+            Self::Webhook { status } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+        }
+    }
+}
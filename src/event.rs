@@ -0,0 +1,252 @@
+use crate::{
+    format::{format_duration, Event, EventKind, Session},
+    locale::Catalog,
+};
+use rdc_connections::RemoteDesktopSessionState;
+use std::{fmt, net::IpAddr};
+use tokio::time::Duration;
+
+/// where a client connects from, named once the address is resolved
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAddress {
+    pub ip: IpAddr,
+    pub name: Option<String>,
+}
+
+impl fmt::Display for ClientAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.ip),
+            None => write!(f, "{}", self.ip),
+        }
+    }
+}
+
+/// a session transition as detected by `ClientStateMap::update_state`, before any formatting
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    Connected {
+        client: String,
+        user: String,
+        /// where the client connects from, when the server tells
+        address: Option<ClientAddress>,
+        /// unknown for clients seen for the first time
+        from: Option<RemoteDesktopSessionState>,
+    },
+    Disconnected {
+        client: String,
+        /// last known one, session info may not carry the user anymore
+        user: String,
+        address: Option<ClientAddress>,
+        from: RemoteDesktopSessionState,
+        state: RemoteDesktopSessionState,
+        /// unknown for sessions which were already active when first seen
+        duration: Option<Duration>,
+    },
+    UserChanged {
+        client: String,
+        from: String,
+        to: String,
+    },
+    /// into a state other than active which notifications were asked for
+    StateChanged {
+        client: String,
+        user: String,
+        /// unknown for clients seen for the first time
+        from: Option<RemoteDesktopSessionState>,
+        state: RemoteDesktopSessionState,
+    },
+}
+
+impl SessionEvent {
+    /// the user the session belongs to now
+    pub fn user(&self) -> &str {
+        match self {
+            Self::Connected { user, .. }
+            | Self::Disconnected { user, .. }
+            | Self::StateChanged { user, .. } => user,
+            Self::UserChanged { to, .. } => to,
+        }
+    }
+
+    pub fn client(&self) -> &str {
+        match self {
+            Self::Connected { client, .. }
+            | Self::Disconnected { client, .. }
+            | Self::UserChanged { client, .. }
+            | Self::StateChanged { client, .. } => client,
+        }
+    }
+
+    /// the state the client was in before, when known
+    pub fn previous_state(&self) -> Option<RemoteDesktopSessionState> {
+        match self {
+            Self::Connected { from, .. } | Self::StateChanged { from, .. } => *from,
+            Self::Disconnected { from, .. } => Some(*from),
+            Self::UserChanged { .. } => None,
+        }
+    }
+
+    pub fn address(&self) -> Option<&ClientAddress> {
+        match self {
+            Self::Connected { address, .. } | Self::Disconnected { address, .. } => {
+                address.as_ref()
+            }
+            _ => None,
+        }
+    }
+
+    /// names the client address through `resolve`, keeping the bare ip when it has no name
+    pub fn resolve_address<F: Fn(IpAddr) -> Option<String>>(&mut self, resolve: F) {
+        if let Self::Connected {
+            address: Some(address),
+            ..
+        }
+        | Self::Disconnected {
+            address: Some(address),
+            ..
+        } = self
+        {
+            address.name = resolve(address.ip);
+        }
+    }
+
+    /// formats the event for a session on `server`, in the language of `catalog`
+    pub fn to_event(&self, server: &str, catalog: &Catalog) -> Event {
+        let (kind, text, client, state, duration) = match self {
+            Self::Connected {
+                client,
+                user,
+                address,
+                ..
+            } => (
+                EventKind::Connected,
+                catalog.text(
+                    "connected",
+                    &[
+                        (
+                            "label",
+                            &connection_label(catalog, client, user, address.as_ref()),
+                        ),
+                        ("client", client),
+                        ("user", user),
+                        ("server", server),
+                    ],
+                ),
+                client,
+                RemoteDesktopSessionState::Active,
+                None,
+            ),
+            Self::Disconnected {
+                client,
+                user,
+                address,
+                state,
+                duration,
+                ..
+            } => {
+                let label = connection_label(catalog, client, user, address.as_ref());
+                let mut values = vec![
+                    ("label", label.as_str()),
+                    ("client", client),
+                    ("user", user),
+                    ("server", server),
+                ];
+                let text = match duration {
+                    Some(d) => {
+                        let duration = format_duration(*d);
+                        values.push(("duration", &duration));
+                        catalog.text("disconnected_after", &values)
+                    }
+                    None => catalog.text("disconnected", &values),
+                };
+                (EventKind::Disconnected, text, client, *state, *duration)
+            }
+            Self::UserChanged { client, from, to } => (
+                EventKind::UserChanged,
+                catalog.text(
+                    "user_changed",
+                    &[
+                        ("client", client),
+                        ("from", from),
+                        ("to", to),
+                        ("server", server),
+                    ],
+                ),
+                client,
+                RemoteDesktopSessionState::Active,
+                None,
+            ),
+            Self::StateChanged {
+                client,
+                user,
+                from,
+                state,
+            } => {
+                let label = client_label(catalog, client, user);
+                let state_name = format!("{:?}", state);
+                let mut values = vec![
+                    ("label", label.as_str()),
+                    ("client", client),
+                    ("user", user),
+                    ("state", &state_name),
+                    ("server", server),
+                ];
+                let text = match from {
+                    Some(from) => {
+                        let from = format!("{:?}", from);
+                        values.push(("from", &from));
+                        catalog.text("state_changed_from", &values)
+                    }
+                    None => catalog.text("state_changed", &values),
+                };
+                (EventKind::StateChanged, text, client, *state, None)
+            }
+        };
+        let mut event = Event::new(kind, text);
+        event.session = Some(Session {
+            server: server.to_owned(),
+            client: client.to_owned(),
+            user: self.user().to_owned(),
+            address: self.address().map(|a| a.to_string()).unwrap_or_default(),
+            state: format!("{:?}", state),
+            previous_state: self
+                .previous_state()
+                .map(|s| format!("{:?}", s))
+                .unwrap_or_default(),
+            duration,
+        });
+        event
+    }
+}
+
+/// like `client_label`, with the address the client connects from
+fn connection_label(
+    catalog: &Catalog,
+    client: &str,
+    user: &str,
+    address: Option<&ClientAddress>,
+) -> String {
+    let details: Vec<String> = (!user.is_empty())
+        .then(|| catalog.text("user_label", &[("user", user)]))
+        .into_iter()
+        .chain(address.map(|a| a.to_string()))
+        .collect();
+    if details.is_empty() {
+        format!("'{}'", client)
+    } else {
+        format!("'{}' ({})", client, details.join(", "))
+    }
+}
+
+fn client_label(catalog: &Catalog, client: &str, user: &str) -> String {
+    if user.is_empty() {
+        format!("'{}'", client)
+    } else {
+        format!(
+            "'{}' ({})",
+            client,
+            catalog.text("user_label", &[("user", user)])
+        )
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::mpsc as std_mpsc,
+    thread,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// writes within this time of each other count as one change
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// whether `event` is about the file named `file_name` in the watched directory
+pub fn affects(event: &DebouncedEvent, file_name: &OsStr) -> bool {
+    let is_file = |path: &PathBuf| path.file_name() == Some(file_name);
+    match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Remove(path) => is_file(path),
+        DebouncedEvent::Rename(from, to) => is_file(from) || is_file(to),
+        _ => false,
+    }
+}
+
+/// sends on `changed` whenever the server file is written, replaced or removed; its directory
+/// is watched rather than the file, editors and tools tend to replace the file on save
+pub fn watch_server_file(path: &Path, changed: mpsc::UnboundedSender<()>) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("server file {:?} has no file name", path))?
+        .to_owned();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let (tx, rx) = std_mpsc::channel();
+    let mut file_watcher = watcher(tx, DEBOUNCE)
+        .map_err(|e| anyhow!("server file {:?} could not be watched. {:?}", path, e))?;
+    file_watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(|e| anyhow!("server file {:?} could not be watched. {:?}", path, e))?;
+    info!("watching server file {:?} for changes", path);
+    let path = path.to_owned();
+    thread::spawn(move || {
+        // events stop once the watcher is dropped, so it lives as long as this thread
+        let _file_watcher = file_watcher;
+        for event in rx {
+            match event {
+                DebouncedEvent::Error(e, _) => error!("watching server file failed. {:?}", e),
+                event if affects(&event, &file_name) => {
+                    warn!("server file {:?} changed, reloading", path);
+                    if changed.send(()).is_err() {
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_server_file_changes_reload() {
+        use ::notify::DebouncedEvent;
+        let file_name = std::ffi::OsStr::new("servers.txt");
+        let path = |name: &str| Path::new("/etc/rdc").join(name);
+        assert!(affects(
+            &DebouncedEvent::Write(path("servers.txt")),
+            file_name
+        ));
+        assert!(affects(
+            &DebouncedEvent::Rename(path("servers.txt.tmp"), path("servers.txt")),
+            file_name
+        ));
+        assert!(!affects(
+            &DebouncedEvent::Write(path("other.txt")),
+            file_name
+        ));
+        assert!(!affects(
+            &DebouncedEvent::Chmod(path("servers.txt")),
+            file_name
+        ));
+    }
+}
@@ -0,0 +1,42 @@
+/// include/exclude lists of case-insensitive glob patterns, `*` matches any run of characters
+/// and `?` a single one
+#[derive(Debug, Clone, Default)]
+pub struct NameFilter {
+    include: Vec<Vec<char>>,
+    exclude: Vec<Vec<char>>,
+}
+
+impl NameFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Self {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| p.to_lowercase().chars().collect())
+                .collect()
+        };
+        Self {
+            include: compile(include),
+            exclude: compile(exclude),
+        }
+    }
+
+    /// exclusion wins over inclusion, an empty include list lets every name through
+    pub fn allows(&self, name: &str) -> bool {
+        let name: Vec<char> = name.to_lowercase().chars().collect();
+        if self.exclude.iter().any(|p| glob_match(p, &name)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|p| glob_match(p, &name))
+    }
+}
+
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| glob_match(rest, &name[skip..])),
+        Some((&p, rest)) => match name.split_first() {
+            Some((&c, name_rest)) if p == '?' || p == c => glob_match(rest, name_rest),
+            _ => false,
+        },
+    }
+}
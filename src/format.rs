@@ -0,0 +1,672 @@
+use crate::locale::Catalog;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{slice, str::FromStr, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EventKind {
+    Connected,
+    Disconnected,
+    UserChanged,
+    /// the session entered one of the states opted into with `--notify-states`
+    StateChanged,
+    /// every session of a server was gone at once, reported instead of each disconnect
+    SessionsLost,
+    Unreachable,
+    Reachable,
+    Info,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Severity {
+    #[default]
+    Normal,
+    /// worth a louder alert, e.g. a privileged account logging in
+    High,
+}
+
+/// a single notification produced by a poll, or by the notifier itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub kind: EventKind,
+    pub text: String,
+    pub session: Option<Session>,
+    /// when the event was detected, which may be well before it gets posted
+    pub at: DateTime<Local>,
+    #[serde(default)]
+    pub severity: Severity,
+    /// a connection outside business hours, marked by the formatters
+    #[serde(default)]
+    pub after_hours: bool,
+}
+
+/// the rdc session an event is about
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Session {
+    pub server: String,
+    pub client: String,
+    pub user: String,
+    /// empty when unknown
+    #[serde(default)]
+    pub address: String,
+    pub state: String,
+    /// empty when unknown
+    #[serde(default)]
+    pub previous_state: String,
+    pub duration: Option<Duration>,
+}
+
+impl Event {
+    pub fn new<S: Into<String>>(kind: EventKind, text: S) -> Self {
+        Self {
+            kind,
+            text: text.into(),
+            session: None,
+            at: Local::now(),
+            severity: Severity::Normal,
+            after_hours: false,
+        }
+    }
+
+    /// the text as posted, behind its indicator with `emoji`, otherwise only with a warning
+    /// sign in front when it happened after hours
+    pub fn marked_text(&self, emoji: Option<Emoji>) -> String {
+        match emoji {
+            Some(emoji) => match self.indicator(emoji) {
+                Some(indicator) => format!("{} {}", indicator, self.text),
+                None => self.text.clone(),
+            },
+            None if self.after_hours => format!("⚠️ {}", self.text),
+            None => self.text.clone(),
+        }
+    }
+
+    /// what the event is about at a glance, the most urgent of severity, business hours and
+    /// kind; `None` for events worth no indicator
+    pub fn indicator(&self, emoji: Emoji) -> Option<&'static str> {
+        let (unicode, shortcode) = match (self.severity, self.kind) {
+            (Severity::High, _) | (_, EventKind::Unreachable) => ("🚨", ":rotating_light:"),
+            _ if self.after_hours => ("⚠️", ":warning:"),
+            (_, EventKind::Connected | EventKind::Reachable) => ("✅", ":white_check_mark:"),
+            (_, EventKind::Disconnected | EventKind::SessionsLost) => ("🔌", ":electric_plug:"),
+            _ => return None,
+        };
+        Some(match emoji {
+            Emoji::Unicode => unicode,
+            Emoji::Shortcode => shortcode,
+        })
+    }
+
+    /// ISO-8601 local time of detection
+    pub fn timestamp(&self) -> String {
+        self.at.format("%Y-%m-%dT%H:%M:%S%:z").to_string()
+    }
+}
+
+/// how indicators are written, chat services with shortcodes get those
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Emoji {
+    Unicode,
+    Shortcode,
+}
+
+/// user supplied texts with `{client}`, `{user}`, `{address}`, `{server}`, `{state}`,
+/// `{previous_state}` and `{duration}` placeholders
+#[derive(Debug, Clone, Default)]
+pub struct MessageTemplates {
+    pub connect: Option<String>,
+    pub disconnect: Option<String>,
+    /// put in front of high severity events, e.g. `@here`
+    pub alert_prefix: Option<String>,
+    /// texts of events without a template
+    pub catalog: Catalog,
+}
+
+impl MessageTemplates {
+    /// renders the template of the event, if any, and marks high severity events
+    pub fn apply(&self, event: &mut Event) {
+        if let Some(text) = self.render(event) {
+            event.text = text;
+        }
+        if let (Severity::High, Some(prefix)) = (event.severity, &self.alert_prefix) {
+            event.text = format!("{} {}", prefix, event.text);
+        }
+    }
+
+    /// returns `None` when no template is configured for this kind of event
+    pub fn render(&self, event: &Event) -> Option<String> {
+        let template = match event.kind {
+            EventKind::Connected => self.connect.as_ref(),
+            EventKind::Disconnected => self.disconnect.as_ref(),
+            _ => None,
+        }?;
+        let session = event.session.as_ref()?;
+        let duration = session.duration.map(format_duration).unwrap_or_default();
+        Some(fill(
+            template,
+            &[
+                ("client", &session.client),
+                ("user", &session.user),
+                ("address", &session.address),
+                ("server", &session.server),
+                ("state", &session.state),
+                ("previous_state", &session.previous_state),
+                ("duration", &duration),
+            ],
+        ))
+    }
+}
+
+/// replaces `{name}` placeholders by their values in a single pass, so values which contain
+/// placeholders themselves, e.g. a user named `{server}`, are kept as they are; unknown
+/// placeholders are kept too
+pub fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &after[..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{}s", m, s),
+        (h, m, _) => format!("{}h{}m", h, m),
+    }
+}
+
+/// `count` followed by `one` or `many`, e.g. `1 session` and `0 sessions`
+pub fn plural(count: usize, one: &str, many: &str) -> String {
+    format!("{} {}", count, if count == 1 { one } else { many })
+}
+
+/// body of a webhook post
+#[derive(Debug)]
+pub enum Payload {
+    /// posted in a plain adaptive card
+    Text(String),
+    Json(Value),
+}
+
+pub trait MessageFormatter: Send + Sync {
+    /// formats all events into the body of a single post
+    fn format_batch(&self, events: &[Event]) -> Payload;
+
+    fn format(&self, event: &Event) -> Payload {
+        self.format_batch(slice::from_ref(event))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Plain,
+    Slack,
+    Discord,
+    Teams,
+    /// a json body of the user's own, see `GenericFormatter`
+    Generic,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "slack" => Ok(Self::Slack),
+            "discord" => Ok(Self::Discord),
+            "teams" => Ok(Self::Teams),
+            "generic" => Ok(Self::Generic),
+            _ => Err(anyhow!("unknown message format '{}'", s)),
+        }
+    }
+}
+
+impl Format {
+    /// `body_template` is only used by, and required for, the generic format; `emoji` puts
+    /// indicators in front of event texts
+    pub fn formatter(self, body_template: Option<&str>, emoji: bool) -> Box<dyn MessageFormatter> {
+        let unicode = emoji.then_some(Emoji::Unicode);
+        match self {
+            Self::Plain => Box::new(PlainFormatter { emoji: unicode }),
+            Self::Slack => Box::new(SlackFormatter {
+                emoji: emoji.then_some(Emoji::Shortcode),
+            }),
+            // webhook posts to discord show shortcodes as they are, only typed ones turn into emoji
+            Self::Discord => Box::new(DiscordFormatter { emoji: unicode }),
+            Self::Teams => Box::new(TeamsFormatter { emoji: unicode }),
+            Self::Generic => Box::new(GenericFormatter::new(
+                body_template.unwrap_or_default(),
+                unicode,
+            )),
+        }
+    }
+}
+
+pub struct PlainFormatter {
+    emoji: Option<Emoji>,
+}
+
+impl MessageFormatter for PlainFormatter {
+    fn format_batch(&self, events: &[Event]) -> Payload {
+        let lines: Vec<String> = events
+            .iter()
+            .map(|e| format!("[{}] {}", e.timestamp(), e.marked_text(self.emoji)))
+            .collect();
+        Payload::Text(lines.join("\n"))
+    }
+}
+
+pub struct SlackFormatter {
+    emoji: Option<Emoji>,
+}
+
+impl MessageFormatter for SlackFormatter {
+    /// every event gets its own attachment, whose fallback stands in for it in notifications
+    fn format_batch(&self, events: &[Event]) -> Payload {
+        let attachments: Vec<Value> = events
+            .iter()
+            .map(|event| {
+                let color = match event.kind {
+                    EventKind::Connected => "#2eb886",
+                    EventKind::Disconnected => "#e01e5a",
+                    EventKind::UserChanged => "#ecb22e",
+                    EventKind::StateChanged => "#439fe0",
+                    EventKind::SessionsLost => "#a30200",
+                    EventKind::Unreachable => "#a30200",
+                    EventKind::Reachable => "#2eb886",
+                    EventKind::Info => "#808080",
+                };
+                let text = event.marked_text(self.emoji);
+                json!({
+                    "fallback": text,
+                    "color": color,
+                    "ts": event.at.timestamp(),
+                    "blocks": [{
+                        "type": "section",
+                        "text": { "type": "mrkdwn", "text": text },
+                    }],
+                })
+            })
+            .collect();
+        Payload::Json(json!({ "attachments": attachments }))
+    }
+}
+
+pub struct DiscordFormatter {
+    emoji: Option<Emoji>,
+}
+
+impl DiscordFormatter {
+    const TITLE_LIMIT: usize = 256;
+    const DESCRIPTION_LIMIT: usize = 4096;
+    const CONTENT_LIMIT: usize = 2000;
+    const EMBED_LIMIT: usize = 10;
+    /// characters of titles and descriptions across all embeds of a message
+    const TOTAL_LIMIT: usize = 6000;
+}
+
+impl MessageFormatter for DiscordFormatter {
+    /// every event gets its own embed, only those beyond the embed or total limit are listed in
+    /// content
+    fn format_batch(&self, events: &[Event]) -> Payload {
+        let mut total = 0;
+        let embeds: Vec<Value> = events
+            .iter()
+            .take(Self::EMBED_LIMIT)
+            .map_while(|event| {
+                let (title, color) = match event.kind {
+                    EventKind::Connected => ("RDC session connected", 0x2ecc71),
+                    EventKind::Disconnected => ("RDC session disconnected", 0xe74c3c),
+                    EventKind::UserChanged => ("RDC session user changed", 0xf1c40f),
+                    EventKind::StateChanged => ("RDC session state changed", 0x3498db),
+                    EventKind::SessionsLost => ("RDC sessions lost", 0x992d22),
+                    EventKind::Unreachable => ("RDC server unreachable", 0x992d22),
+                    EventKind::Reachable => ("RDC server reachable", 0x2ecc71),
+                    EventKind::Info => ("RDC notifier", 0x95a5a6),
+                };
+                let title = truncate(title, Self::TITLE_LIMIT);
+                let description = truncate(&event.marked_text(self.emoji), Self::DESCRIPTION_LIMIT);
+                total += title.chars().count() + description.chars().count();
+                (total <= Self::TOTAL_LIMIT).then(|| {
+                    json!({
+                        "title": title,
+                        "description": description,
+                        "color": color,
+                        "timestamp": event.timestamp(),
+                    })
+                })
+            })
+            .collect();
+        // embeds are taken in order, so the events left out are the ones after them
+        let left_out = &events[embeds.len()..];
+        let mut body = json!({ "embeds": embeds });
+        if !left_out.is_empty() {
+            body["content"] =
+                truncate(&joined_text(left_out, self.emoji), Self::CONTENT_LIMIT).into();
+        }
+        Payload::Json(body)
+    }
+}
+
+pub struct TeamsFormatter {
+    emoji: Option<Emoji>,
+}
+
+impl MessageFormatter for TeamsFormatter {
+    fn format_batch(&self, events: &[Event]) -> Payload {
+        let sections: Vec<Value> = events
+            .iter()
+            .map(|event| {
+                let facts = match &event.session {
+                    Some(session) => json!([
+                        { "name": "Server", "value": session.server },
+                        { "name": "Client", "value": session.client },
+                        { "name": "User", "value": session.user },
+                        { "name": "State", "value": session.state },
+                        { "name": "Previous state", "value": session.previous_state },
+                    ]),
+                    None => json!([]),
+                };
+                json!({
+                    "activityTitle": event.marked_text(self.emoji),
+                    "activitySubtitle": event.timestamp(),
+                    "facts": facts,
+                })
+            })
+            .collect();
+        // a card has a single color, so mixed batches fall back to the neutral one
+        let color = match events.first() {
+            Some(first) if events.iter().all(|e| e.kind == first.kind) => Self::color(first.kind),
+            _ => Self::color(EventKind::Info),
+        };
+        Payload::Json(json!({
+            "@type": "MessageCard",
+            "@context": "https://schema.org/extensions",
+            "themeColor": color,
+            "summary": joined_text(events, self.emoji),
+            "sections": sections,
+        }))
+    }
+}
+
+impl TeamsFormatter {
+    fn color(kind: EventKind) -> &'static str {
+        match kind {
+            EventKind::Connected => "2EB886",
+            EventKind::Disconnected => "E01E5A",
+            EventKind::UserChanged => "ECB22E",
+            EventKind::StateChanged => "439FE0",
+            EventKind::SessionsLost => "A30200",
+            EventKind::Unreachable => "A30200",
+            EventKind::Reachable => "2EB886",
+            EventKind::Info => "808080",
+        }
+    }
+}
+
+/// renders a json body of the user's own, for endpoints which are none of the chat services;
+/// `{client}`, `{user}`, `{server}`, `{state}`, `{timestamp}`, `{kind}` and `{text}` are
+/// substituted json escaped, so they belong inside string literals like `{"who": "{user}"}`
+pub struct GenericFormatter {
+    template: String,
+    emoji: Option<Emoji>,
+}
+
+impl GenericFormatter {
+    pub fn new(template: &str, emoji: Option<Emoji>) -> Self {
+        Self {
+            template: template.to_owned(),
+            emoji,
+        }
+    }
+
+    /// errors when the template does not render to json
+    pub fn check(template: &str) -> Result<()> {
+        let sample = Self::new(template, None).render(&Event::new(EventKind::Info, "sample"));
+        serde_json::from_str::<Value>(&sample)
+            .map(drop)
+            .map_err(|e| anyhow!("body template does not render to json. {}", e))
+    }
+
+    fn render(&self, event: &Event) -> String {
+        let session = event.session.clone().unwrap_or_default();
+        let escape = |s: &str| {
+            let quoted = Value::from(s).to_string();
+            quoted[1..quoted.len() - 1].to_owned()
+        };
+        fill(
+            &self.template,
+            &[
+                ("client", &escape(&session.client)),
+                ("user", &escape(&session.user)),
+                ("server", &escape(&session.server)),
+                ("state", &escape(&session.state)),
+                ("timestamp", &event.timestamp()),
+                ("kind", &format!("{:?}", event.kind)),
+                ("text", &escape(&event.marked_text(self.emoji))),
+            ],
+        )
+    }
+}
+
+impl MessageFormatter for GenericFormatter {
+    /// a single event is the rendered template, a batch an array of them
+    fn format_batch(&self, events: &[Event]) -> Payload {
+        let mut bodies: Vec<Value> = events
+            .iter()
+            .map(|event| {
+                let body = self.render(event);
+                // checked at startup, but a user's text may still be one no template expects
+                serde_json::from_str(&body).unwrap_or(Value::String(body))
+            })
+            .collect();
+        if bodies.len() == 1 {
+            Payload::Json(bodies.remove(0))
+        } else {
+            Payload::Json(Value::Array(bodies))
+        }
+    }
+}
+
+fn joined_text(events: &[Event], emoji: Option<Emoji>) -> String {
+    events
+        .iter()
+        .map(|e| e.marked_text(emoji))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// cuts text down to `limit` characters, marking the cut with an ellipsis
+fn truncate(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        text.to_owned()
+    } else {
+        let mut cut: String = text.chars().take(limit - 1).collect();
+        cut.push('…');
+        cut
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{event::SessionEvent, locale::Catalog};
+
+    #[test]
+    fn placeholders_in_values_are_not_filled_again() {
+        assert_eq!(
+            fill(
+                "{user} on {server}, {unknown} {",
+                &[("user", "{server}"), ("server", "S1")]
+            ),
+            "{server} on S1, {unknown} {"
+        );
+        let mut event = Event::new(EventKind::Connected, "{server}");
+        event.session = Some(Session {
+            server: "SERVER01".to_owned(),
+            client: "PC01".to_owned(),
+            user: "{server}".to_owned(),
+            ..Session::default()
+        });
+        let templates = MessageTemplates {
+            connect: Some("{user} joined {server}".to_owned()),
+            ..MessageTemplates::default()
+        };
+        assert_eq!(
+            templates.render(&event).unwrap(),
+            "{server} joined SERVER01"
+        );
+        let generic = GenericFormatter::new(r#"{"who": "{user}", "text": "{text}"}"#, None);
+        match generic.format(&event) {
+            Payload::Json(body) => {
+                assert_eq!(body["who"], "{server}");
+                assert_eq!(body["text"], "{server}");
+            }
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        assert_eq!(
+            Catalog::default().text("user_label", &[("user", "{user}")]),
+            "user {user}"
+        );
+    }
+
+    #[test]
+    fn discord_embeds_stay_within_the_total_limit() {
+        let events: Vec<Event> = (0..10)
+            .map(|_| Event::new(EventKind::Connected, "x".repeat(1500)))
+            .collect();
+        let body = match (DiscordFormatter { emoji: None }).format_batch(&events) {
+            Payload::Json(body) => body,
+            payload => panic!("unexpected payload {:?}", payload),
+        };
+        let embeds = body["embeds"].as_array().unwrap();
+        let total: usize = embeds
+            .iter()
+            .map(|e| {
+                e["title"].as_str().unwrap().chars().count()
+                    + e["description"].as_str().unwrap().chars().count()
+            })
+            .sum();
+        assert_eq!(embeds.len(), 3);
+        assert!(total <= DiscordFormatter::TOTAL_LIMIT);
+        let content = body["content"].as_str().unwrap();
+        assert!(
+            content.starts_with('x') && content.chars().count() <= DiscordFormatter::CONTENT_LIMIT
+        );
+        let body = match (DiscordFormatter { emoji: None }).format_batch(&events[..1]) {
+            Payload::Json(body) => body,
+            payload => panic!("unexpected payload {:?}", payload),
+        };
+        assert!(body.get("content").is_none());
+    }
+
+    #[test]
+    fn teams_facts_show_the_session_state() {
+        let mut event = Event::new(EventKind::StateChanged, "'PC01' is now idle");
+        event.session = Some(Session {
+            server: "SERVER01".to_owned(),
+            client: "PC01".to_owned(),
+            state: "Idle".to_owned(),
+            previous_state: "Active".to_owned(),
+            ..Session::default()
+        });
+        let body = match (TeamsFormatter { emoji: None }).format(&event) {
+            Payload::Json(body) => body,
+            payload => panic!("unexpected payload {:?}", payload),
+        };
+        let facts = &body["sections"][0]["facts"];
+        assert_eq!(facts[3]["name"], "State");
+        assert_eq!(facts[3]["value"], "Idle");
+        assert_eq!(facts[4]["value"], "Active");
+    }
+
+    #[test]
+    fn generic_body_escapes_values() {
+        let template = r#"{"who": "{user}", "where": "{server}", "what": "{kind}"}"#;
+        assert!(GenericFormatter::check(template).is_ok());
+        assert!(GenericFormatter::check("{user} logged in").is_err());
+        let event = SessionEvent::Connected {
+            client: "PC01".to_owned(),
+            user: "CORP\\o\"neil".to_owned(),
+            address: None,
+            from: None,
+        }
+        .to_event("SERVER01", &Catalog::default());
+        match GenericFormatter::new(template, None).format(&event) {
+            Payload::Json(body) => {
+                assert_eq!(body["who"], "CORP\\o\"neil");
+                assert_eq!(body["where"], "SERVER01");
+                assert_eq!(body["what"], "Connected");
+            }
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn emoji_indicators_lead_event_texts() {
+        let mut event = Event::new(EventKind::Connected, "'PC01' is now connected");
+        assert_eq!(event.marked_text(None), "'PC01' is now connected");
+        assert_eq!(
+            event.marked_text(Some(Emoji::Unicode)),
+            "✅ 'PC01' is now connected"
+        );
+        event.after_hours = true;
+        assert_eq!(event.marked_text(None), "⚠️ 'PC01' is now connected");
+        assert_eq!(event.indicator(Emoji::Shortcode), Some(":warning:"));
+        event.severity = Severity::High;
+        assert_eq!(event.indicator(Emoji::Unicode), Some("🚨"));
+        assert_eq!(
+            Event::new(EventKind::Info, "alive").indicator(Emoji::Unicode),
+            None
+        );
+        let disconnect = Event::new(EventKind::Disconnected, "'PC01' is disconnected");
+        match Format::Slack.formatter(None, true).format(&disconnect) {
+            Payload::Json(body) => {
+                assert!(body.get("text").is_none());
+                assert_eq!(
+                    body["attachments"][0]["fallback"],
+                    ":electric_plug: 'PC01' is disconnected"
+                );
+            }
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        match Format::Plain.formatter(None, false).format(&disconnect) {
+            Payload::Text(text) => assert!(text.ends_with("] 'PC01' is disconnected")),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn counts_are_pluralized() {
+        assert_eq!(plural(0, "session", "sessions"), "0 sessions");
+        assert_eq!(plural(1, "session", "sessions"), "1 session");
+        assert_eq!(plural(2, "session", "sessions"), "2 sessions");
+        assert_eq!(plural(1, "retry", "retries"), "1 retry");
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{
+    format::{plural, Event},
+    metrics, LiveFeed, ServerClientMapShared,
+};
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use log::{error, info, warn};
+use rdc_connections::RemoteDesktopSessionState;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::{sync::broadcast::error::RecvError, time::Duration};
+
+#[derive(Serialize)]
+struct ActiveSession {
+    client: String,
+    user: String,
+    /// unknown for sessions which were already active when first seen
+    connected_secs: Option<u64>,
+}
+
+/// answers `GET /health` from the last successful poll of each server, `GET /sessions` with
+/// the sessions active right now, `GET /events` with a stream of session events, and
+/// `GET /metrics`
+pub struct HealthReporter {
+    state_map: ServerClientMapShared,
+    periods: HashMap<String, Duration>,
+    feed: LiveFeed,
+}
+
+impl HealthReporter {
+    /// healthy as long as at least one server was polled within twice its period
+    fn report(&self) -> (StatusCode, Value) {
+        let now = Local::now();
+        let mut servers = Map::new();
+        let mut alive = false;
+        for (server, period) in &self.periods {
+            let last_poll = self
+                .state_map
+                .get(server)
+                .and_then(|s| s.lock().unwrap().last_poll);
+            let stale = match last_poll {
+                Some(at) => (now - at).to_std().unwrap_or_default() > *period * 2,
+                None => true,
+            };
+            alive |= !stale;
+            servers.insert(
+                server.clone(),
+                json!({
+                    "last_poll": last_poll.map(|at| at.to_rfc3339()),
+                    "stale": stale,
+                }),
+            );
+        }
+        let status = if alive {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, json!({ "alive": alive, "servers": servers }))
+    }
+
+    /// active clients per server, each server is locked only while its sessions are copied
+    fn sessions(&self) -> Value {
+        let snapshot: HashMap<String, Vec<ActiveSession>> = self
+            .state_map
+            .snapshot()
+            .into_iter()
+            .map(|(server, state)| {
+                let clients = state.lock().unwrap();
+                let active = clients
+                    .data
+                    .values()
+                    .filter(|d| d.state == RemoteDesktopSessionState::Active)
+                    .map(|d| ActiveSession {
+                        client: d.name.clone(),
+                        user: d.user.clone(),
+                        connected_secs: d.connected_at.map(|at| at.elapsed().as_secs()),
+                    })
+                    .collect();
+                (server, active)
+            })
+            .collect();
+        json!({ "servers": snapshot })
+    }
+
+    /// server-sent events, one `data:` line of json per session event
+    fn event_stream(&self) -> Response<Body> {
+        let mut events = self.feed.subscribe();
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(
+                            "event stream subscriber lagged, {} dropped",
+                            plural(missed as usize, "event", "events")
+                        );
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let chunk = format!("data: {}\n\n", event_json(&event));
+                if sender.send_data(chunk.into()).await.is_err() {
+                    // subscriber went away
+                    break;
+                }
+            }
+        });
+        Response::builder()
+            .header("content-type", "text/event-stream")
+            .header("cache-control", "no-cache")
+            .body(body)
+            .unwrap() // headers are always valid
+    }
+
+    fn respond(&self, req: &Request<Body>) -> Response<Body> {
+        let (status, content_type, body) = match req.uri().path() {
+            "/events" => return self.event_stream(),
+            "/health" => {
+                let (status, body) = self.report();
+                (status, "application/json".to_owned(), body.to_string())
+            }
+            "/sessions" => (
+                StatusCode::OK,
+                "application/json".to_owned(),
+                self.sessions().to_string(),
+            ),
+            "/metrics" => {
+                let (content_type, body) = metrics::gather();
+                (StatusCode::OK, content_type, body)
+            }
+            _ => (
+                StatusCode::NOT_FOUND,
+                "application/json".to_owned(),
+                json!({ "error": "not found" }).to_string(),
+            ),
+        };
+        Response::builder()
+            .status(status)
+            .header("content-type", content_type)
+            .body(Body::from(body))
+            .unwrap() // status and header are always valid
+    }
+}
+
+fn event_json(event: &Event) -> Value {
+    let session = event.session.clone().unwrap_or_default();
+    json!({
+        "kind": format!("{:?}", event.kind),
+        "text": event.text,
+        "at": event.timestamp(),
+        "server": session.server,
+        "client": session.client,
+        "user": session.user,
+        "state": session.state,
+        "duration_secs": session.duration.map(|d| d.as_secs()),
+    })
+}
+
+pub fn spawn_health_server(
+    addr: SocketAddr,
+    state_map: ServerClientMapShared,
+    periods: HashMap<String, Duration>,
+    feed: LiveFeed,
+) -> Result<()> {
+    let reporter = Arc::new(HealthReporter {
+        state_map,
+        periods,
+        feed,
+    });
+    let make_svc = make_service_fn(move |_| {
+        let reporter = reporter.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let reporter = reporter.clone();
+                async move { Ok::<_, Infallible>(reporter.respond(&req)) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| anyhow!("health server could not bind to {}. {:?}", addr, e))?
+        .serve(make_svc);
+    info!(
+        "serving health check, sessions, events and metrics on http://{}",
+        addr
+    );
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("health server stopped. {:?}", e);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,2659 @@
+mod backoff;
+mod config;
+mod control;
+mod delivery;
+mod error;
+mod event;
+mod filewatch;
+mod filter;
+mod format;
+mod health;
+mod locale;
+mod logfile;
+mod metrics;
+mod notify;
+mod persist;
+mod quiet;
+mod resolve;
+mod schedule;
+mod source;
+mod webhook;
+
+use anyhow::{anyhow, Result};
+use backoff::Backoff;
+use chrono::{DateTime, Local, NaiveTime};
+use config::{parse_log_level, process_cmd_args, Command, Escalation, LogFormat, UserInput};
+use control::Mutes;
+use delivery::{DeadLetters, Delivery, DeliveryQueue, Outbox};
+use error::NotifierError;
+use event::{ClientAddress, SessionEvent};
+use filter::NameFilter;
+use format::{plural, Event, EventKind, MessageTemplates, Severity};
+use log::{debug, error, info, warn};
+use logfile::RotatingFile;
+use notify::{CsvNotifier, Notifier, Notifiers, SqliteNotifier, SyslogNotifier};
+use quiet::{BusinessHours, QuietGate};
+use rdc_connections::{RemoteDesktopSessionInfo, RemoteDesktopSessionState};
+use resolve::Resolver;
+use schedule::PollSchedule;
+use serde_json::{json, Map, Value};
+use slog::{o, Drain, Filter, FnValue, Logger, PushFnValue, Record};
+use slog_async::Async as LogAsync;
+use source::{SessionSource, WtsServer};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    env, future, io,
+    net::IpAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+use tokio::{
+    signal,
+    sync::{broadcast, mpsc, watch, Semaphore},
+    time::{interval_at, sleep_until, timeout, timeout_at, Duration, Instant, Interval},
+};
+use webhook::{mask_url, WebhookRouter};
+
+type MsgSender = Arc<WebhookRouter>;
+type Templates = Arc<MessageTemplates>;
+type Quiet = Arc<QuietGate>;
+type MutesShared = Arc<Mutes>;
+/// session events as they are detected, for live subscribers
+type LiveFeed = broadcast::Sender<Event>;
+type ServerClientMapShared = Arc<ServerStates>;
+type ServerClientMap = HashMap<String, ClientStateMap>;
+type ServerState = Arc<Mutex<ClientStateMap>>;
+type ShutdownToken = watch::Receiver<bool>;
+type FormatDrain = Box<dyn Drain<Ok = (), Err = io::Error> + Send>;
+
+/// every server behind a lock of its own, so polls of different servers do not wait for each
+/// other; the map itself is only locked for writing when servers are added or removed
+#[derive(Debug, Default)]
+struct ServerStates {
+    servers: RwLock<HashMap<String, ServerState>>,
+}
+
+impl ServerStates {
+    fn get(&self, server: &str) -> Option<ServerState> {
+        self.servers.read().unwrap().get(server).cloned()
+    }
+
+    fn insert(&self, server: String, state: ClientStateMap) {
+        self.servers
+            .write()
+            .unwrap()
+            .insert(server, Arc::new(Mutex::new(state)));
+    }
+
+    /// every server with its state, to be locked one at a time while the others keep polling
+    fn snapshot(&self) -> Vec<(String, ServerState)> {
+        self.servers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(server, state)| (server.clone(), state.clone()))
+            .collect()
+    }
+}
+
+/// where session events go besides the webhook, whether they are posted or not
+#[derive(Clone)]
+struct EventFeed {
+    live: LiveFeed,
+    notifiers: Option<Notifiers>,
+}
+
+impl EventFeed {
+    fn publish(&self, event: &Event) {
+        // fails only when nobody is subscribed
+        let _ = self.live.send(event.clone());
+        if let Some(notifiers) = &self.notifiers {
+            notifiers.notify(event.clone());
+        }
+    }
+}
+
+/// the clients of a server as last polled
+#[derive(Debug)]
+pub struct ClientStateMap {
+    data: HashMap<String, ClientData>,
+    baseline_done: bool,
+    last_poll: Option<DateTime<Local>>,
+    policy: Arc<ClientPolicy>,
+    /// consecutive failed queries, alerted on once it reaches `unreachable_after`
+    failures: u32,
+    summary: SummaryCounters,
+    /// most active sessions at once since `peak_since`
+    peak_active: usize,
+    peak_since: DateTime<Local>,
+}
+
+/// how the clients of every server are reported, taken from the configuration and replaced as
+/// a whole when it is reloaded
+#[derive(Debug)]
+struct ClientPolicy {
+    debounce: Duration,
+    user_filter: NameFilter,
+    client_filter: NameFilter,
+    unreachable_after: u32,
+    /// the first poll only records a baseline instead of reporting what it finds
+    silent_first_poll: bool,
+    /// the first poll does not report sessions it finds active, their disconnects still are
+    skip_existing: bool,
+    /// whether clients unknown to the first poll are reported, for their session or state
+    notify_on_first_seen: bool,
+    /// clients not active for this long are forgotten, kept forever when not given
+    stale_after: Option<Duration>,
+    /// states besides active which clients entering them are reported for
+    notify_states: Vec<RemoteDesktopSessionState>,
+    /// users whose connections are high severity
+    vip_users: Option<NameFilter>,
+    /// connections outside them are high severity
+    business_hours: Option<BusinessHours>,
+    /// disconnects in a single poll leaving no session, which are reported as one, 0 never
+    coalesce_disconnects: u32,
+    /// (dis)connections of a client within `flap_window` beyond which its notifications are
+    /// suppressed, 0 never
+    flap_threshold: u32,
+    flap_window: Duration,
+    /// reminders of sessions open too long, the first one a user matches applies
+    escalations: Vec<Escalation>,
+    /// users are known by their bare name, without the domain
+    strip_domain: bool,
+    /// daily time the peak starts over, never when not given
+    peak_reset: Option<NaiveTime>,
+}
+
+/// what happened on a server since the last summary
+#[derive(Debug, Default)]
+struct SummaryCounters {
+    connects: u32,
+    disconnects: u32,
+    peak_active: usize,
+}
+
+impl SummaryCounters {
+    fn count(&mut self, events: &[SessionEvent]) {
+        for event in events {
+            match event {
+                SessionEvent::Connected { .. } => self.connects += 1,
+                SessionEvent::Disconnected { .. } => self.disconnects += 1,
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClientData {
+    /// as first reported, entries are keyed by `client_key`
+    name: String,
+    state: RemoteDesktopSessionState,
+    user: String,
+    connected_at: Option<Instant>,
+    /// when the client was first seen on the other side of its last notified state
+    pending_since: Option<Instant>,
+    /// when the client was first seen not active, entries are dropped after `stale_after`
+    inactive_since: Option<Instant>,
+    /// where the client last connected from
+    address: Option<IpAddr>,
+    /// (dis)connections within the flap window, oldest first
+    flaps: VecDeque<Instant>,
+    /// notifications are suppressed until it calms down
+    flapping: bool,
+    /// the session an escalation reminded of, by when it started, and how many times
+    reminded: Option<(Instant, u32)>,
+}
+
+/// only active sessions have a client attached, `Idle` is a WinStation waiting for a client to
+/// connect rather than a user who stepped away
+fn in_session(state: RemoteDesktopSessionState) -> bool {
+    state == RemoteDesktopSessionState::Active
+}
+
+/// windows host names are case-insensitive, so `WS01` and `ws01` are the same client
+fn client_key(client: &str) -> String {
+    client.to_uppercase()
+}
+
+impl ClientData {
+    /// returns when the change started, once it has persisted for the whole window
+    fn settled(&mut self, window: Duration) -> Option<Instant> {
+        let since = *self.pending_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < window {
+            return None;
+        }
+        self.pending_since = None;
+        Some(since)
+    }
+
+    /// follows a known client to the state it is reported in now
+    fn update(
+        &mut self,
+        policy: &ClientPolicy,
+        info: &RemoteDesktopSessionInfo,
+    ) -> Vec<SessionEvent> {
+        let client = &info.client_info.client;
+        let user = policy.user(info);
+        let current_state = info.state;
+        let address = source::client_address(&info.client_info.address);
+        let was_in_session = in_session(self.state);
+        let is_in_session = in_session(current_state);
+        let mut events = Vec::new();
+        if was_in_session == is_in_session {
+            // flapped back before the change settled, nothing to report
+            self.pending_since = None;
+            if is_in_session && !user.is_empty() && !self.user.is_empty() && user != self.user {
+                // someone else took over the session, their time starts now
+                self.connected_at = Some(Instant::now());
+                events.push(SessionEvent::UserChanged {
+                    client: client.to_owned(),
+                    from: self.user.clone(),
+                    to: user.to_owned(),
+                });
+            }
+            // flips in and out of the session are reported as (dis)connections already
+            if self.state != current_state {
+                let user = if user.is_empty() { &self.user } else { user };
+                events.extend(policy.state_event(client, user, Some(self.state), current_state));
+            }
+        } else {
+            let since = match self.settled(policy.debounce) {
+                Some(since) => since,
+                None => return events,
+            };
+            if !is_in_session
+                && current_state != RemoteDesktopSessionState::Disconnected
+                && policy.notify_states.contains(&current_state)
+            {
+                // opted into, so reported as what it is rather than a disconnect
+                events.extend(policy.state_event(
+                    client,
+                    &self.user,
+                    Some(self.state),
+                    current_state,
+                ));
+                self.connected_at = None;
+            } else if is_in_session {
+                self.connected_at = Some(since);
+                events.push(SessionEvent::Connected {
+                    client: client.to_owned(),
+                    user: user.to_owned(),
+                    address: address.map(|ip| ClientAddress { ip, name: None }),
+                    from: Some(self.state),
+                });
+            } else {
+                // session info may not carry the user anymore, so report the last known one
+                events.push(SessionEvent::Disconnected {
+                    client: client.to_owned(),
+                    user: self.user.clone(),
+                    address: self.address.map(|ip| ClientAddress { ip, name: None }),
+                    from: self.state,
+                    state: current_state,
+                    duration: self.connected_at.map(|at| at.elapsed()),
+                });
+                self.connected_at = None;
+            }
+        }
+        self.state = current_state;
+        if address.is_some() {
+            self.address = address;
+        }
+        if !user.is_empty() {
+            self.user = user.to_owned();
+        }
+        events
+    }
+}
+
+impl Default for ClientPolicy {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::ZERO,
+            user_filter: NameFilter::default(),
+            client_filter: NameFilter::default(),
+            unreachable_after: 0,
+            silent_first_poll: false,
+            skip_existing: false,
+            notify_on_first_seen: true,
+            stale_after: None,
+            notify_states: Vec::new(),
+            vip_users: None,
+            business_hours: None,
+            coalesce_disconnects: 0,
+            flap_threshold: 0,
+            flap_window: Duration::ZERO,
+            escalations: Vec::new(),
+            strip_domain: false,
+            peak_reset: None,
+        }
+    }
+}
+
+impl ClientPolicy {
+    fn new(input: &UserInput) -> Self {
+        Self {
+            debounce: input.debounce,
+            user_filter: input.user_filter(),
+            client_filter: input.client_filter(),
+            unreachable_after: input.unreachable_after,
+            silent_first_poll: input.silent_first_poll,
+            skip_existing: input.skip_existing,
+            notify_on_first_seen: input.notify_on_first_seen,
+            stale_after: input.stale_after,
+            notify_states: input.notify_states.clone(),
+            vip_users: input.vip_filter(),
+            business_hours: input.business_hours,
+            coalesce_disconnects: input.coalesce_disconnects,
+            flap_threshold: input.flap_threshold,
+            flap_window: input.flap_window,
+            escalations: input.escalations.clone(),
+            strip_domain: input.strip_domain,
+            peak_reset: input.peak_reset,
+        }
+    }
+
+    /// a client leaving the session or staying out of it is reported for the states opted into
+    fn state_event(
+        &self,
+        client: &str,
+        user: &str,
+        from: Option<RemoteDesktopSessionState>,
+        state: RemoteDesktopSessionState,
+    ) -> Option<SessionEvent> {
+        (!in_session(state) && self.notify_states.contains(&state)).then(|| {
+            SessionEvent::StateChanged {
+                client: client.to_owned(),
+                user: user.to_owned(),
+                from,
+                state,
+            }
+        })
+    }
+
+    /// the full name is still in the logged session info
+    fn user<'a>(&self, info: &'a RemoteDesktopSessionInfo) -> &'a str {
+        if self.strip_domain {
+            source::bare_user(&info.client_info.user)
+        } else {
+            &info.client_info.user
+        }
+    }
+}
+
+impl Default for ClientStateMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientStateMap {
+    pub fn new() -> Self {
+        Self {
+            data: HashMap::new(),
+            baseline_done: false,
+            last_poll: None,
+            policy: Arc::default(),
+            failures: 0,
+            summary: SummaryCounters::default(),
+            peak_active: 0,
+            peak_since: Local::now(),
+        }
+    }
+
+    /// applies the settings of a (re)loaded configuration, keeping the known sessions
+    fn configure(&mut self, policy: Arc<ClientPolicy>) {
+        // known clients may no longer be monitored
+        self.data
+            .retain(|client, _| policy.client_filter.allows(client));
+        self.policy = policy;
+    }
+
+    fn query_failed(&mut self, server: &str) -> Option<Event> {
+        self.failures += 1;
+        (self.policy.unreachable_after > 0 && self.failures == self.policy.unreachable_after).then(
+            || {
+                let mut event = Event::new(
+                    EventKind::Unreachable,
+                    format!("'{}' is unreachable", server),
+                );
+                event.severity = Severity::High;
+                event
+            },
+        )
+    }
+
+    /// replaces the disconnects of a server which lost every session at once, likely by a
+    /// restart, with a single event
+    fn coalesce_disconnects(&self, server: &str, events: &mut Vec<SessionEvent>) -> Option<Event> {
+        let is_disconnect = |e: &SessionEvent| matches!(e, SessionEvent::Disconnected { .. });
+        let lost = events.iter().filter(|e| is_disconnect(e)).count();
+        if self.policy.coalesce_disconnects == 0
+            || lost < self.policy.coalesce_disconnects as usize
+            || self.data.values().any(|d| in_session(d.state))
+        {
+            return None;
+        }
+        events.retain(|e| !is_disconnect(e));
+        Some(Event::new(
+            EventKind::SessionsLost,
+            format!(
+                "'{}' lost {} (server restart?)",
+                server,
+                plural(lost, "active session", "active sessions")
+            ),
+        ))
+    }
+
+    /// drops the events of clients (dis)connecting more than `flap_threshold` times within
+    /// `flap_window`, announcing when they start and stop flapping instead
+    fn suppress_flapping(&mut self, events: &mut Vec<SessionEvent>) -> Vec<Event> {
+        if self.policy.flap_threshold == 0 {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        for event in events.iter() {
+            if let SessionEvent::Connected { client, .. }
+            | SessionEvent::Disconnected { client, .. } = event
+            {
+                if let Some(data) = self.data.get_mut(&client_key(client)) {
+                    data.flaps.push_back(now);
+                }
+            }
+        }
+        let mut notices = Vec::new();
+        for data in self.data.values_mut() {
+            while data
+                .flaps
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= self.policy.flap_window)
+            {
+                data.flaps.pop_front();
+            }
+            if !data.flapping && data.flaps.len() > self.policy.flap_threshold as usize {
+                data.flapping = true;
+                notices.push(Event::new(
+                    EventKind::Info,
+                    format!("'{}' is flapping, notifications suppressed", data.name),
+                ));
+            } else if data.flapping && data.flaps.is_empty() {
+                data.flapping = false;
+                notices.push(Event::new(
+                    EventKind::Info,
+                    format!("'{}' is stable again, notifications resumed", data.name),
+                ));
+            }
+        }
+        let data = &self.data;
+        events.retain(|e| {
+            !data
+                .get(&client_key(e.client()))
+                .is_some_and(|d| d.flapping)
+        });
+        notices
+    }
+
+    /// reminders of sessions still open past their escalation, sessions first seen already open
+    /// have no known start and are never escalated
+    fn escalate(&mut self, server: &str) -> Vec<Event> {
+        let mut reminders = Vec::new();
+        for data in self.data.values_mut() {
+            let connected_at = match data.connected_at {
+                Some(at) if in_session(data.state) => at,
+                _ => continue,
+            };
+            let escalation = match self
+                .policy
+                .escalations
+                .iter()
+                .find(|e| e.applies_to(&data.user))
+            {
+                Some(escalation) => escalation,
+                None => continue,
+            };
+            let sent = match data.reminded {
+                Some((at, sent)) if at == connected_at => sent,
+                _ => 0,
+            };
+            let due = match escalation.every {
+                Some(every) => escalation.after + every * sent,
+                None if sent == 0 => escalation.after,
+                None => continue,
+            };
+            let open = connected_at.elapsed();
+            if open < due {
+                continue;
+            }
+            data.reminded = Some((connected_at, sent + 1));
+            let mut event = Event::new(
+                EventKind::Info,
+                format!(
+                    "'{}' (user {}) is still connected to '{}' after {}",
+                    data.name,
+                    data.user,
+                    server,
+                    format::format_duration(open)
+                ),
+            );
+            event.severity = Severity::High;
+            reminders.push(event);
+        }
+        reminders
+    }
+
+    /// keeps the peak of active sessions, starting over once the daily reset time has passed
+    fn record_active(&mut self, active: usize) {
+        let now = Local::now();
+        let rollover = self.policy.peak_reset.and_then(|at| {
+            let today = now.date().and_time(at)?;
+            if today <= now {
+                Some(today)
+            } else {
+                now.date().pred().and_time(at)
+            }
+        });
+        if rollover.is_some_and(|rollover| self.peak_since < rollover) {
+            self.peak_active = 0;
+            self.peak_since = now;
+        }
+        self.peak_active = self.peak_active.max(active);
+        self.summary.peak_active = self.summary.peak_active.max(active);
+    }
+
+    fn query_succeeded(&mut self, server: &str) -> Option<Event> {
+        let was_reported =
+            self.policy.unreachable_after > 0 && self.failures >= self.policy.unreachable_after;
+        self.failures = 0;
+        was_reported.then(|| {
+            Event::new(
+                EventKind::Reachable,
+                format!("'{}' is reachable again", server),
+            )
+        })
+    }
+
+    /// records a poll's sessions, returning what changed since the previous one
+    pub fn update_state(&mut self, client_info: &[RemoteDesktopSessionInfo]) -> Vec<SessionEvent> {
+        let client_info = unique_sessions(client_info);
+        let policy = self.policy.clone();
+        let mut events = Vec::new();
+        for info in client_info
+            .iter()
+            .filter(|i| policy.client_filter.allows(&i.client_info.client))
+        {
+            let key = client_key(&info.client_info.client);
+            match self.data.get_mut(&key) {
+                Some(data) => events.extend(data.update(&policy, info)),
+                None => events.extend(self.first_seen(key, info)),
+            }
+        }
+        events.extend(self.disconnect_vanished(&client_info));
+        self.forget_stale();
+        self.finish_poll(events)
+    }
+
+    /// starts tracking a client, reporting it unless the first poll is to keep quiet about it
+    fn first_seen(&mut self, key: String, info: &RemoteDesktopSessionInfo) -> Vec<SessionEvent> {
+        let client = &info.client_info.client;
+        let user = self.policy.user(info);
+        let state = info.state;
+        let address = source::client_address(&info.client_info.address);
+        let is_in_session = in_session(state);
+        // sessions found active by the very first poll started at an unknown time
+        let observed_at = self.baseline_done.then(Instant::now);
+        self.data.insert(
+            key,
+            ClientData {
+                name: client.to_owned(),
+                state,
+                user: user.to_owned(),
+                connected_at: if is_in_session { observed_at } else { None },
+                pending_since: None,
+                inactive_since: None,
+                address,
+                flaps: VecDeque::new(),
+                flapping: false,
+                reminded: None,
+            },
+        );
+        let mut events = Vec::new();
+        // after the first poll, clients seen for the first time have just connected
+        if !self.baseline_done && !self.policy.notify_on_first_seen {
+            return events;
+        }
+        if is_in_session {
+            events.push(SessionEvent::Connected {
+                client: client.to_owned(),
+                user: user.to_owned(),
+                address: address.map(|ip| ClientAddress { ip, name: None }),
+                from: None,
+            });
+        }
+        events.extend(self.policy.state_event(client, user, None, state));
+        events
+    }
+
+    /// in case client is not found, looked up once per poll rather than per known client
+    fn disconnect_vanished(
+        &mut self,
+        client_info: &[&RemoteDesktopSessionInfo],
+    ) -> Vec<SessionEvent> {
+        let present: HashSet<String> = client_info
+            .iter()
+            .map(|i| client_key(&i.client_info.client))
+            .collect();
+        let mut events = Vec::new();
+        for (key, data) in &mut self.data {
+            if present.contains(key) {
+                continue;
+            }
+            if !in_session(data.state) {
+                data.pending_since = None;
+            } else if data.settled(self.policy.debounce).is_some() {
+                let from = data.state;
+                data.state = RemoteDesktopSessionState::Disconnected;
+                events.push(SessionEvent::Disconnected {
+                    client: data.name.clone(),
+                    user: data.user.clone(),
+                    address: data.address.map(|ip| ClientAddress { ip, name: None }),
+                    from,
+                    state: data.state,
+                    duration: data.connected_at.map(|at| at.elapsed()),
+                });
+                data.connected_at = None;
+            }
+        }
+        events
+    }
+
+    /// a forgotten client coming back is reported as a new connection
+    fn forget_stale(&mut self) {
+        for data in self.data.values_mut() {
+            if in_session(data.state) {
+                data.inactive_since = None;
+            } else {
+                data.inactive_since.get_or_insert_with(Instant::now);
+            }
+        }
+        if let Some(stale_after) = self.policy.stale_after {
+            self.data.retain(|_, data| {
+                data.inactive_since
+                    .is_none_or(|since| since.elapsed() < stale_after)
+            });
+        }
+    }
+
+    /// drops what is not to be reported of the poll's events and records that it took place
+    fn finish_poll(&mut self, mut events: Vec<SessionEvent>) -> Vec<SessionEvent> {
+        // state is still tracked for filtered users, only their notifications are dropped
+        events.retain(|event| self.policy.user_filter.allows(event.user()));
+        if self.policy.silent_first_poll && !self.baseline_done {
+            events.clear();
+        }
+        if self.policy.skip_existing && !self.baseline_done {
+            // tracked like any other session from now on
+            events.retain(|e| !matches!(e, SessionEvent::Connected { from: None, .. }));
+        }
+        self.baseline_done = true;
+        self.last_poll = Some(Local::now());
+        events
+    }
+}
+
+/// runs what the command line asks for, polling until stopped unless it is a subcommand
+pub async fn run() -> Result<()> {
+    let mut input = process_cmd_args()?;
+    let _scope_guard = slog_scope::set_global_logger(get_logger(&input).unwrap());
+    slog_stdlog::init().unwrap();
+    info!("active_rdc_webhook_notifier {}", config::VERSION);
+    info!("{:?}", redacted_args());
+    warn_lacking_texts(&input);
+    let mut msg_sender = Arc::new(WebhookRouter::new(&input)?);
+    match input.command {
+        Command::Run => {}
+        Command::Validate => return validate_servers(&input).await,
+        Command::TestWebhook => return test_webhooks(&msg_sender).await,
+        Command::Snapshot => return print_snapshot(&input).await,
+        Command::ReplayDeadletter => return replay_dead_letters(&input, &msg_sender).await,
+    }
+    let mut templates: Templates = Arc::new(input.templates());
+    let mut resolver = input.resolve_clients.then(|| Arc::new(Resolver::new()));
+    let mut quiet: Quiet = Arc::new(QuietGate::new(input.quiet_hours, input.quiet_summary));
+    // subscribers lagging behind this much miss events rather than hold up polling
+    let (live, _) = broadcast::channel(256);
+    let mut notifiers: Vec<Arc<dyn Notifier>> = Vec::new();
+    if let Some(path) = &input.db {
+        notifiers.push(Arc::new(SqliteNotifier::open(path)?));
+    }
+    if let Some(path) = &input.csv {
+        notifiers.push(Arc::new(CsvNotifier::open(path)?));
+    }
+    if let Some(target) = &input.syslog {
+        notifiers.push(Arc::new(SyslogNotifier::connect(target)?));
+    }
+    #[cfg(windows)]
+    if input.eventlog {
+        notifiers.push(Arc::new(notify::EventLogNotifier::register()?));
+    }
+    let (notifiers, notifier_task) = if notifiers.is_empty() {
+        (None, None)
+    } else {
+        let (notifiers, task) = notify::spawn_notifiers(notifiers);
+        (Some(notifiers), Some(task))
+    };
+    let feed = EventFeed { live, notifiers };
+    let mut restored = match &input.state_file {
+        Some(path) => persist::load_state(path).unwrap_or_else(|e| {
+            error!("previous state could not be restored. {:?}", e);
+            HashMap::new()
+        }),
+        None => HashMap::new(),
+    };
+    let state_map = ServerClientMapShared::default();
+    let policy = Arc::new(ClientPolicy::new(&input));
+    for server in &input.servers {
+        let mut client_state_map = restored
+            .remove(&server.name)
+            .unwrap_or_else(ClientStateMap::new);
+        client_state_map.configure(policy.clone());
+        state_map.insert(server.name.clone(), client_state_map);
+    }
+    if let Some(addr) = input.health_addr {
+        let periods = input
+            .servers
+            .iter()
+            .map(|s| (s.name.clone(), input.period_of(s)))
+            .collect();
+        health::spawn_health_server(addr, state_map.clone(), periods, feed.live.clone())?;
+    }
+    let mutes: MutesShared = Arc::new(Mutes::default());
+    if let Some(addr) = input.control_addr {
+        control::spawn_control_server(addr, mutes.clone()).await?;
+    }
+    if input.notify_startup {
+        let servers: Vec<&str> = input.servers.iter().map(|s| s.name.as_str()).collect();
+        let event = Event::new(
+            EventKind::Info,
+            format!("RDC notifier started, monitoring: {}", servers.join(", ")),
+        );
+        post_notification(&msg_sender, &event, "startup notification").await;
+    }
+    let outbox = Outbox::load(
+        input.outbox_file.clone(),
+        input.outbox_ttl,
+        DeadLetters::new(input.dead_letter_file.clone()),
+    );
+    let (queue, delivery_worker) = delivery::spawn_delivery_worker(
+        input.queue_capacity,
+        input.queue_full,
+        input.failure_policy(),
+        msg_sender.clone(),
+        outbox,
+    );
+    let mut shutdown = shutdown_on_signal();
+    // set once shutdown is requested, work still in flight by then is given up on
+    let mut give_up_at = None;
+    let mut cycle_given_up = false;
+    let (reload_tx, mut reload) = mpsc::unbounded_channel();
+    reload_on_sighup(reload_tx.clone());
+    // keeps watching the path given at startup, a new one only applies after a restart
+    if let Some(path) = &input.server_file {
+        if let Err(e) = filewatch::watch_server_file(path, reload_tx) {
+            error!("{:?}", e);
+        }
+    }
+    let mut schedule = PollSchedule::new(&input);
+    let mut heartbeat = input
+        .heartbeat
+        .map(|period| interval_at(Instant::now() + period, period));
+    let mut summary = input
+        .summary_interval
+        .map(|period| interval_at(Instant::now() + period, period));
+    'polling: loop {
+        let due_servers = schedule.due_servers();
+        let cycle_start = Instant::now();
+        let ctx = PollContext {
+            msg_sender: msg_sender.clone(),
+            state_map: state_map.clone(),
+            templates: templates.clone(),
+            resolver: resolver.clone(),
+            quiet: quiet.clone(),
+            mutes: mutes.clone(),
+            feed: feed.clone(),
+            queue: queue.clone(),
+            query_timeout: input.query_timeout,
+            max_concurrent: input.max_concurrent,
+            batch: input.batch,
+            shutdown: shutdown.clone(),
+        };
+        let refresh = refresh_all_connections(ctx, due_servers.clone(), WtsServer::new);
+        if finish_or_give_up(
+            refresh,
+            &mut shutdown,
+            input.shutdown_timeout,
+            &mut give_up_at,
+        )
+        .await
+        .is_none()
+        {
+            cycle_given_up = true;
+            break;
+        }
+        save_state(&input, &state_map);
+        if let Some(summary) = quiet.take_summary() {
+            post_notification(&msg_sender, &summary, "quiet hours summary").await;
+        }
+        let cycle_duration = cycle_start.elapsed();
+        metrics::CYCLE_DURATION.set(cycle_duration.as_secs_f64());
+        // next polls are scheduled from now on, so a slow cycle is not followed by another one
+        // right away, it only delays them
+        if let Some(period) = schedule.shortest_period(&due_servers) {
+            if cycle_duration > period {
+                metrics::SLOW_CYCLES.inc();
+                warn!(
+                    "poll cycle took {:?}, longer than the period of {:?}",
+                    cycle_duration, period
+                );
+            }
+        }
+        schedule.adapt(has_open_sessions(&state_map));
+        schedule.polled(&due_servers);
+        info!("{:?}", state_map);
+        if *shutdown.borrow() || input.once {
+            break;
+        }
+        loop {
+            tokio::select! {
+                _ = sleep_until(schedule.next_wake()) => break,
+                _ = shutdown.changed() => break 'polling,
+                _ = next_tick(&mut heartbeat) => post_heartbeat(&msg_sender, &state_map).await,
+                _ = next_tick(&mut summary) => post_summary(&msg_sender, &state_map).await,
+                _ = next_reload(&mut reload) => match reload_config(&state_map, &mut schedule) {
+                    Ok((new_input, router)) => {
+                        for option in input.restart_only_changes(&new_input) {
+                            warn!("'{}' changed, the new value only applies after a restart", option);
+                        }
+                        msg_sender = Arc::new(router);
+                        warn_lacking_texts(&new_input);
+                        templates = Arc::new(new_input.templates());
+                        quiet = Arc::new(
+                            quiet.reconfigured(new_input.quiet_hours, new_input.quiet_summary),
+                        );
+                        let kept = resolver.take();
+                        resolver = new_input
+                            .resolve_clients
+                            .then(|| kept.unwrap_or_else(|| Arc::new(Resolver::new())));
+                        if new_input.heartbeat != input.heartbeat {
+                            heartbeat = new_input
+                                .heartbeat
+                                .map(|period| interval_at(Instant::now() + period, period));
+                        }
+                        if new_input.summary_interval != input.summary_interval {
+                            summary = new_input
+                                .summary_interval
+                                .map(|period| interval_at(Instant::now() + period, period));
+                        }
+                        input = new_input;
+                    }
+                    Err(e) => error!(
+                        "configuration could not be reloaded, keeping the current one. {:?}",
+                        e
+                    ),
+                },
+            }
+        }
+    }
+    info!("shutting down");
+    let mut pending = if cycle_given_up {
+        usize::try_from(metrics::QUERIES_IN_FLIGHT.get()).unwrap_or_default()
+    } else {
+        0
+    };
+    // events already polled still get posted
+    drop(queue);
+    let mut delivery_worker = delivery_worker;
+    match finish_or_give_up(
+        &mut delivery_worker,
+        &mut shutdown,
+        input.shutdown_timeout,
+        &mut give_up_at,
+    )
+    .await
+    {
+        Some(Ok(())) => {}
+        Some(Err(e)) => error!("delivery worker failed. {:?}", e),
+        None => {
+            delivery_worker.abort();
+            pending += 1;
+        }
+    }
+    // and so do events already polled reach the other notifiers
+    drop(feed);
+    if let Some(mut notifier_task) = notifier_task {
+        match finish_or_give_up(
+            &mut notifier_task,
+            &mut shutdown,
+            input.shutdown_timeout,
+            &mut give_up_at,
+        )
+        .await
+        {
+            Some(Ok(())) => {}
+            Some(Err(e)) => error!("notifier task failed. {:?}", e),
+            None => {
+                notifier_task.abort();
+                pending += 1;
+            }
+        }
+    }
+    if pending > 0 {
+        warn!(
+            "shutdown timeout of {:?} passed, exiting anyway with {} still pending",
+            input.shutdown_timeout,
+            plural(pending, "task", "tasks")
+        );
+    }
+    save_state(&input, &state_map);
+    if input.notify_shutdown {
+        let event = Event::new(EventKind::Info, "RDC notifier stopped");
+        post_notification(&msg_sender, &event, "shutdown notification").await;
+    }
+    Ok(())
+}
+
+/// the locale file is read along with the configuration, before logging is set up, so what it
+/// lacks is told once logging is
+fn warn_lacking_texts(input: &UserInput) {
+    if let Some(path) = &input.locale {
+        for key in input.catalog.lacking() {
+            warn!(
+                "locale file {:?} lacks '{}', falling back to english",
+                path, key
+            );
+        }
+    }
+}
+
+fn has_open_sessions(state_map: &ServerClientMapShared) -> bool {
+    state_map.snapshot().iter().any(|(_, state)| {
+        state
+            .lock()
+            .unwrap()
+            .data
+            .values()
+            .any(|c| in_session(c.state))
+    })
+}
+
+/// to the default webhook, as it is not about a single server
+async fn post_notification(msg_sender: &WebhookRouter, event: &Event, what: &str) {
+    match msg_sender.default_client() {
+        Some(client) => {
+            if let Err(e) = client.post(event).await {
+                error!("{} could not be posted. {:?}", what, e);
+            }
+        }
+        None => warn!("{} not posted, there is no default 'webhook url'", what),
+    }
+}
+
+/// posts a test message to every webhook, failing if any of them did not take it
+async fn test_webhooks(msg_sender: &WebhookRouter) -> Result<()> {
+    let event = Event::new(EventKind::Info, "test message from RDC notifier");
+    let mut failed = 0;
+    for client in msg_sender.clients() {
+        match client.post(&event).await {
+            Ok(()) => println!("{}: ok", client.masked_url()),
+            Err(e) => {
+                failed += 1;
+                println!("{}: failed. {:?}", client.masked_url(), e);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} webhook(s) could not be posted to", failed));
+    }
+    Ok(())
+}
+
+async fn replay_dead_letters(input: &UserInput, msg_sender: &WebhookRouter) -> Result<()> {
+    let path = input
+        .dead_letter_file
+        .as_ref()
+        .ok_or_else(|| anyhow!("'dead letter file' is needed to replay it"))?;
+    let (delivered, left) = delivery::replay_dead_letters(path, msg_sender).await?;
+    println!(
+        "{} delivered, {} left",
+        plural(delivered, "dead letter", "dead letters"),
+        left
+    );
+    if left > 0 {
+        return Err(anyhow!(
+            "{} could not be delivered",
+            plural(left, "dead letter", "dead letters")
+        ));
+    }
+    Ok(())
+}
+
+/// queries every server once, all at the same time, without tracking any state
+async fn query_servers(input: &UserInput) -> Vec<(String, Result<Vec<RemoteDesktopSessionInfo>>)> {
+    let tasks: Vec<_> = input
+        .servers
+        .iter()
+        .map(|server| {
+            let name = server.name.clone();
+            let task =
+                tokio::task::spawn_blocking(move || query_with_retry(&mut WtsServer::new(&name)));
+            (server.name.clone(), task)
+        })
+        .collect();
+    let mut results = Vec::new();
+    for (server, task) in tasks {
+        let result = match input.query_timeout {
+            Some(t) => timeout(t, task)
+                .await
+                .unwrap_or_else(|_| Ok(Err(anyhow!("query timed out after {:?}", t)))),
+            None => task.await,
+        };
+        results.push((server, result.map_err(anyhow::Error::from).and_then(|r| r)));
+    }
+    results
+}
+
+/// the configuration is valid once here, what is left is whether every server answers
+async fn validate_servers(input: &UserInput) -> Result<()> {
+    let mut failed = 0;
+    for (server, result) in query_servers(input).await {
+        match result {
+            Ok(sessions) => println!(
+                "{}: ok, {}",
+                server,
+                plural(sessions.len(), "session", "sessions")
+            ),
+            Err(e) => {
+                failed += 1;
+                println!("{}: failed. {:?}", server, e);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} server(s) could not be queried", failed));
+    }
+    Ok(())
+}
+
+/// prints the sessions of every server as json, servers which could not be queried with the
+/// error instead
+async fn print_snapshot(input: &UserInput) -> Result<()> {
+    let servers: Map<String, Value> = query_servers(input)
+        .await
+        .into_iter()
+        .map(|(server, result)| {
+            let value = match result {
+                Ok(sessions) => {
+                    let sessions: Vec<Value> = sessions
+                        .iter()
+                        .map(|s| {
+                            json!({
+                                "session_id": s.session_id,
+                                "client": s.client_info.client,
+                                "user": s.client_info.user,
+                                "address": source::client_address(&s.client_info.address),
+                                "state": format!("{:?}", s.state),
+                            })
+                        })
+                        .collect();
+                    json!({ "sessions": sessions })
+                }
+                Err(e) => json!({ "error": format!("{:?}", e) }),
+            };
+            (server, value)
+        })
+        .collect();
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&json!({ "servers": servers }))?
+    );
+    Ok(())
+}
+
+/// sends on every SIGHUP, never on platforms without it
+fn reload_on_sighup(tx: mpsc::UnboundedSender<()>) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+            Ok(mut hangup) => while hangup.recv().await.is_some() && tx.send(()).is_ok() {},
+            Err(e) => error!("SIGHUP handler could not be installed. {:?}", e),
+        }
+    });
+    #[cfg(not(unix))]
+    drop(tx);
+}
+
+async fn next_reload(reload: &mut mpsc::UnboundedReceiver<()>) {
+    if reload.recv().await.is_none() {
+        future::pending().await
+    }
+}
+
+/// re-reads the configuration, servers still monitored keep their sessions
+fn reload_config(
+    state_map: &ServerClientMapShared,
+    schedule: &mut PollSchedule,
+) -> Result<(UserInput, WebhookRouter)> {
+    let input = process_cmd_args()?;
+    let router = WebhookRouter::new(&input)?;
+    let mut locked_state = state_map.servers.write().unwrap();
+    let removed: Vec<String> = locked_state
+        .keys()
+        .filter(|name| !input.servers.iter().any(|s| &s.name == *name))
+        .cloned()
+        .collect();
+    for name in &removed {
+        locked_state.remove(name);
+    }
+    let mut added = Vec::new();
+    let policy = Arc::new(ClientPolicy::new(&input));
+    for server in &input.servers {
+        let client_state_map = locked_state.entry(server.name.clone()).or_insert_with(|| {
+            added.push(server.name.clone());
+            Arc::new(Mutex::new(ClientStateMap::new()))
+        });
+        client_state_map.lock().unwrap().configure(policy.clone());
+    }
+    schedule.reload(&input);
+    warn!(
+        "configuration reloaded, servers added: {:?}, removed: {:?}",
+        added, removed
+    );
+    Ok((input, router))
+}
+
+/// never completes without an interval
+async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+async fn post_heartbeat(msg_sender: &MsgSender, state_map: &ServerClientMapShared) {
+    let text = {
+        let servers = state_map.snapshot();
+        let active: usize = servers
+            .iter()
+            .map(|(_, state)| {
+                state
+                    .lock()
+                    .unwrap()
+                    .data
+                    .values()
+                    .filter(|d| d.state == RemoteDesktopSessionState::Active)
+                    .count()
+            })
+            .sum();
+        format!(
+            "RDC notifier alive, {} across {}",
+            plural(active, "active session", "active sessions"),
+            plural(servers.len(), "server", "servers")
+        )
+    };
+    let event = Event::new(EventKind::Info, text);
+    post_notification(msg_sender, &event, "heartbeat").await;
+}
+
+/// posts what happened on each server since the last summary and starts counting anew
+async fn post_summary(msg_sender: &MsgSender, state_map: &ServerClientMapShared) {
+    let text = {
+        let mut lines: Vec<String> = state_map
+            .snapshot()
+            .into_iter()
+            .map(|(server, state)| {
+                let mut clients = state.lock().unwrap();
+                let active = clients
+                    .data
+                    .values()
+                    .filter(|d| d.state == RemoteDesktopSessionState::Active)
+                    .count();
+                let summary = std::mem::take(&mut clients.summary);
+                format!(
+                    "'{}': {} active (peak {}, {} since {}), {}, {}",
+                    server,
+                    active,
+                    summary.peak_active,
+                    clients.peak_active,
+                    clients.peak_since.format("%Y-%m-%d %H:%M"),
+                    plural(summary.connects as usize, "connect", "connects"),
+                    plural(summary.disconnects as usize, "disconnect", "disconnects")
+                )
+            })
+            .collect();
+        lines.sort();
+        format!("RDC session summary:\n{}", lines.join("\n"))
+    };
+    let event = Event::new(EventKind::Info, text);
+    post_notification(msg_sender, &event, "summary").await;
+}
+
+fn save_state(input: &UserInput, state_map: &ServerClientMapShared) {
+    if let Some(path) = &input.state_file {
+        if let Err(e) = persist::save_state(path, state_map) {
+            error!("{:?}", e);
+        }
+    }
+}
+
+/// command line as given, minus the secrets in webhook and proxy urls, tokens and headers
+fn redacted_args() -> Vec<String> {
+    fn redact(option: &str, value: &str) -> String {
+        match option {
+            "--url" | "--alert-url" | "--proxy" => mask_url(value),
+            _ => "***".to_owned(),
+        }
+    }
+    const SECRET_OPTIONS: [&str; 6] = [
+        "--url",
+        "--alert-url",
+        "--proxy",
+        "--auth-token",
+        "--header",
+        "--signing-secret",
+    ];
+    let mut option_before: Option<String> = None;
+    env::args()
+        .map(|arg| {
+            if let Some(option) = option_before.take() {
+                return redact(&option, &arg);
+            }
+            match arg.split_once('=') {
+                Some((option, value)) if SECRET_OPTIONS.contains(&option) => {
+                    format!("{}={}", option, redact(option, value))
+                }
+                _ => {
+                    if SECRET_OPTIONS.contains(&arg.as_str()) {
+                        option_before = Some(arg.clone());
+                    }
+                    arg
+                }
+            }
+        })
+        .collect()
+}
+
+/// set on ctrl+c, or SIGTERM as sent by service managers
+fn shutdown_on_signal() -> ShutdownToken {
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        tokio::select! {
+            result = signal::ctrl_c() => match result {
+                Ok(_) => warn!("received ctrl+c, finishing the current poll"),
+                Err(e) => error!("ctrl+c handler could not be installed. {:?}", e),
+            },
+            _ = terminated() => warn!("received SIGTERM, finishing the current poll"),
+        }
+        let _ = tx.send(true);
+    });
+    rx
+}
+
+/// never resolves on platforms without SIGTERM
+async fn terminated() {
+    #[cfg(unix)]
+    match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(mut term) => {
+            term.recv().await;
+            return;
+        }
+        Err(e) => error!("SIGTERM handler could not be installed. {:?}", e),
+    }
+    future::pending().await
+}
+
+async fn shutdown_requested(shutdown: &mut ShutdownToken) {
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            future::pending().await
+        }
+    }
+}
+
+/// awaits `work`, once shutdown is requested only until `give_up_at`, which is set to `timeout`
+/// from then on if not yet; `None` when given up on
+async fn finish_or_give_up<F: future::Future>(
+    work: F,
+    shutdown: &mut ShutdownToken,
+    timeout: Duration,
+    give_up_at: &mut Option<Instant>,
+) -> Option<F::Output> {
+    tokio::pin!(work);
+    let deadline = match give_up_at {
+        Some(deadline) => *deadline,
+        None => {
+            tokio::select! {
+                output = &mut work => return Some(output),
+                _ = shutdown_requested(shutdown) => {}
+            }
+            *give_up_at.insert(Instant::now() + timeout)
+        }
+    };
+    timeout_at(deadline, work).await.ok()
+}
+
+/// colored by level on a terminal, plain when stderr is redirected or `NO_COLOR` is set
+fn term_decorator() -> slog_term::TermDecoratorBuilder {
+    let decorator = slog_term::TermDecorator::new();
+    if env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        decorator.force_plain()
+    } else {
+        decorator
+    }
+}
+
+fn get_logger(input: &UserInput) -> Result<Logger> {
+    // RUST_LOG still wins over the configured level, for those used to it
+    let level = env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| parse_log_level(&level).ok())
+        .or(input.log_level)
+        .unwrap_or(slog::Level::Info);
+    // console never gets chattier than warnings, the log file has the details
+    let term_level = if level.is_at_least(slog::Level::Warning) {
+        level
+    } else {
+        slog::Level::Warning
+    };
+    let logger = {
+        let filtered_term_drain = {
+            let term_drain: FormatDrain = match input.log_format {
+                LogFormat::Text => {
+                    Box::new(slog_term::FullFormat::new(term_decorator().build()).build())
+                }
+                LogFormat::Json => json_drain(io::stderr()),
+            };
+            Filter::new(term_drain, move |rec| rec.level().is_at_least(term_level))
+        };
+        let filtered_file_drain = {
+            let file_drain: FormatDrain = {
+                let log_file_handle = {
+                    let log_file = match &input.log_file {
+                        Some(path) => path.clone(),
+                        None => Path::new(&env::var("LOCALAPPDATA")?)
+                            .join("active_rdc_webhook_notifier.log"),
+                    };
+                    // stdout is for output of subcommands like snapshot
+                    eprintln!("detailed log file path: {:?}", log_file);
+                    RotatingFile::open(&log_file, input.log_max_size.map(|mb| mb * 1024 * 1024))
+                        .map_err(|e| anyhow!("log file could not be opened or created. {:?}", e))?
+                };
+                match input.log_format {
+                    LogFormat::Text => Box::new(
+                        slog_term::FullFormat::new(slog_term::PlainDecorator::new(log_file_handle))
+                            .build(),
+                    ),
+                    LogFormat::Json => json_drain(log_file_handle),
+                }
+            };
+            Filter::new(file_drain, move |rec| rec.level().is_at_least(level))
+        };
+        let drain =
+            LogAsync::new(slog::Duplicate::new(filtered_term_drain, filtered_file_drain).fuse())
+                .build()
+                .fuse();
+        slog::Logger::root(drain, o!())
+    };
+    Ok(logger)
+}
+
+/// one json object per line, key-values of the record are added as fields of their own
+fn json_drain<W: io::Write + Send + 'static>(io: W) -> FormatDrain {
+    Box::new(
+        slog_json::Json::new(io)
+            .add_key_value(o!(
+                "timestamp" => FnValue(|_: &Record| Local::now().to_rfc3339()),
+                "level" => FnValue(|rec: &Record| rec.level().as_str()),
+                // records forwarded from `log` carry its target as tag
+                "target" => PushFnValue(|rec: &Record, ser| {
+                    ser.emit(if rec.tag().is_empty() {
+                        rec.module()
+                    } else {
+                        rec.tag()
+                    })
+                }),
+                "message" => PushFnValue(|rec: &Record, ser| ser.emit(rec.msg())),
+            ))
+            .build(),
+    )
+}
+
+/// what a poll cycle works with besides the servers due, taken afresh every cycle as a reload
+/// may have replaced any of it
+struct PollContext {
+    msg_sender: MsgSender,
+    state_map: ServerClientMapShared,
+    templates: Templates,
+    resolver: Option<Arc<Resolver>>,
+    quiet: Quiet,
+    mutes: MutesShared,
+    feed: EventFeed,
+    queue: DeliveryQueue,
+    query_timeout: Option<Duration>,
+    max_concurrent: Option<usize>,
+    batch: bool,
+    shutdown: ShutdownToken,
+}
+
+/// `open` gives the source of each server, they are queried on the blocking pool
+async fn refresh_all_connections<S, F>(ctx: PollContext, servers: Vec<String>, open: F)
+where
+    S: SessionSource + Send + 'static,
+    F: Fn(&str) -> S,
+{
+    let PollContext {
+        msg_sender,
+        state_map,
+        templates,
+        resolver,
+        quiet,
+        mutes,
+        feed,
+        queue,
+        query_timeout,
+        max_concurrent,
+        batch,
+        shutdown,
+    } = ctx;
+    let limit = max_concurrent.map(|n| Arc::new(Semaphore::new(n)));
+    let mut tasks = Vec::new();
+    for server in servers {
+        let source = open(&server);
+        let limit = limit.clone();
+        let state_map = state_map.clone();
+        let mut shutdown = shutdown.clone();
+        let name = server.clone();
+        // every server waits for its slot in a task of its own, so one held back by the limit
+        // holds up neither the others nor a shutdown
+        let task = tokio::spawn(async move {
+            let _permit = match limit {
+                Some(limit) => tokio::select! {
+                    permit = limit.acquire_owned() => Some(permit.expect("never closed")),
+                    _ = shutdown_requested(&mut shutdown) => None,
+                },
+                None => None,
+            };
+            if *shutdown.borrow() {
+                info!("shutdown requested, skipping query of '{}'", server);
+                return None;
+            }
+            let state = state_map.clone();
+            let name = server.clone();
+            let claimed = Arc::new(AtomicBool::new(false));
+            let claim = claimed.clone();
+            // queries block on RPC calls, they must not hold up a runtime worker meanwhile
+            let mut query = tokio::task::spawn_blocking(move || {
+                let _timer = metrics::POLL_DURATION
+                    .with_label_values(&[&name])
+                    .start_timer();
+                metrics::QUERIES_IN_FLIGHT.inc();
+                let polled = read_active_connections(source, state, &claim);
+                metrics::QUERIES_IN_FLIGHT.dec();
+                polled
+            });
+            // queries held back by the limit get their full time as well
+            let result = match query_timeout {
+                Some(query_timeout) => match timeout(query_timeout, &mut query).await {
+                    Ok(result) => result,
+                    // the answer is being recorded already, it is only a moment away
+                    Err(_) if claimed.swap(true, Ordering::SeqCst) => query.await,
+                    Err(_) => {
+                        // the query keeps its blocking thread but leaves its slot to the next
+                        // server, whatever it returns later is dropped
+                        error!("query of '{}' timed out", server);
+                        Ok(PolledServer::failed(
+                            &server,
+                            query_failed(&state_map, &server),
+                        ))
+                    }
+                },
+                None => query.await,
+            };
+            Some(result)
+        });
+        tasks.push((name, task));
+    }
+    // batches are collected per webhook, so servers sharing one still get a single post
+    let mut batched: HashMap<String, (Vec<Event>, Vec<String>, bool)> = HashMap::new();
+    for (server, task) in tasks {
+        let result = match task.await {
+            Ok(Some(result)) => result,
+            Ok(None) => continue,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(mut polled) => {
+                // after the query, so lookups neither hold a query slot nor count against its
+                // timeout
+                if let Some(resolver) = &resolver {
+                    polled.resolve_addresses(resolver).await;
+                }
+                let mut connection_status = polled.into_events(&templates);
+                info!("messages: {:?}", connection_status);
+                for event in connection_status.iter().filter(|e| e.session.is_some()) {
+                    feed.publish(event);
+                }
+                connection_status.retain(|e| match &e.session {
+                    Some(session) if mutes.is_muted(&session.client) => {
+                        info!("'{}' is muted, not posting: {}", session.client, e.text);
+                        false
+                    }
+                    _ => true,
+                });
+                if !quiet.pass(&connection_status) {
+                    info!("quiet hours, not posting events of '{}'", server);
+                    continue;
+                }
+                for (alert, events) in split_alerts(&msg_sender, connection_status) {
+                    if batch {
+                        let url = msg_sender.url_for_event(&server, alert).to_owned();
+                        let entry = batched
+                            .entry(url)
+                            .or_insert((Vec::new(), Vec::new(), alert));
+                        entry.0.extend(events);
+                        if !entry.1.contains(&server) {
+                            entry.1.push(server.clone());
+                        }
+                        continue;
+                    }
+                    let delivery = Delivery {
+                        servers: vec![server.clone()],
+                        events,
+                        batch: false,
+                        alert,
+                    };
+                    queue.push(msg_sender.clone(), delivery).await;
+                }
+            }
+            Err(e) => {
+                let e = NotifierError::ServerQuery {
+                    server,
+                    source: e.into(),
+                };
+                error!("{:?}", anyhow::Error::from(e));
+            }
+        }
+    }
+    for (events, servers, alert) in batched.into_values() {
+        let delivery = Delivery {
+            servers,
+            events,
+            batch: true,
+            alert,
+        };
+        queue.push(msg_sender.clone(), delivery).await;
+    }
+}
+
+/// high severity events, and servers reachable again after one of them
+fn is_alert(event: &Event) -> bool {
+    event.severity == Severity::High || event.kind == EventKind::Reachable
+}
+
+/// groups alerts apart when they have a webhook of their own, empty groups are left out
+fn split_alerts(router: &WebhookRouter, events: Vec<Event>) -> Vec<(bool, Vec<Event>)> {
+    let (alerts, events) = if router.has_alert_webhook() {
+        events.into_iter().partition(is_alert)
+    } else {
+        (Vec::new(), events)
+    };
+    [(true, alerts), (false, events)]
+        .into_iter()
+        .filter(|(_, events)| !events.is_empty())
+        .collect()
+}
+
+/// what a server query left to post, session events are formatted once their client addresses
+/// are resolved
+struct PolledServer {
+    server: String,
+    /// about the server rather than a session, e.g. it being reachable again
+    events: Vec<Event>,
+    sessions: Vec<SessionEvent>,
+    vip_users: Option<NameFilter>,
+    business_hours: Option<BusinessHours>,
+}
+
+impl PolledServer {
+    fn failed(server: &str, events: Vec<Event>) -> Self {
+        Self {
+            server: server.to_owned(),
+            events,
+            sessions: Vec::new(),
+            vip_users: None,
+            business_hours: None,
+        }
+    }
+
+    /// names the client addresses, each address is looked up once however many events have it
+    async fn resolve_addresses(&mut self, resolver: &Arc<Resolver>) {
+        let ips: HashSet<IpAddr> = self
+            .sessions
+            .iter()
+            .filter_map(|e| e.address().map(|a| a.ip))
+            .collect();
+        let lookups: Vec<_> = ips
+            .into_iter()
+            .map(|ip| {
+                let resolver = resolver.clone();
+                (ip, tokio::spawn(async move { resolver.name(ip).await }))
+            })
+            .collect();
+        let mut names = HashMap::new();
+        for (ip, lookup) in lookups {
+            names.insert(ip, lookup.await.ok().flatten());
+        }
+        self.sessions
+            .iter_mut()
+            .for_each(|e| e.resolve_address(|ip| names.get(&ip).cloned().flatten()));
+    }
+
+    fn into_events(self, templates: &MessageTemplates) -> Vec<Event> {
+        let Self {
+            server,
+            mut events,
+            sessions,
+            vip_users,
+            business_hours,
+        } = self;
+        sessions.iter().for_each(|session_event| {
+            let mut event = session_event.to_event(&server, &templates.catalog);
+            if let Some(session) = &event.session {
+                slog_scope::info!(
+                    "session event";
+                    "server" => &session.server,
+                    "client" => &session.client,
+                    "user" => &session.user,
+                    "event" => format!("{:?}", event.kind),
+                );
+            }
+            match event.kind {
+                EventKind::Connected => metrics::CONNECT_EVENTS.with_label_values(&[&server]).inc(),
+                EventKind::Disconnected => metrics::DISCONNECT_EVENTS
+                    .with_label_values(&[&server])
+                    .inc(),
+                _ => {}
+            }
+            if let SessionEvent::Connected { user, .. } = session_event {
+                event.after_hours = business_hours.is_some_and(|h| !h.contains(event.at));
+                if event.after_hours || vip_users.as_ref().is_some_and(|v| v.allows(user)) {
+                    event.severity = Severity::High;
+                }
+            }
+            templates.apply(&mut event);
+            events.push(event);
+        });
+        events
+    }
+}
+
+/// `claimed` is set by whichever comes first, the answer being recorded or the poll giving up on
+/// the query; an answer coming after the poll gave up is dropped, as it was counted as failed
+fn read_active_connections<S: SessionSource>(
+    mut server_handle: S,
+    state_map: ServerClientMapShared,
+    claimed: &AtomicBool,
+) -> PolledServer {
+    let mut connection_info = Vec::new();
+    match query_with_retry(&mut server_handle) {
+        Ok(server_info_v) => {
+            info!("{:?}", server_info_v);
+            if server_info_v.is_empty() {
+                debug!(
+                    "'{}' answered with no sessions, clients known from before count as gone",
+                    server_handle.name()
+                );
+            } else {
+                debug!(
+                    "'{}' answered with {}",
+                    server_handle.name(),
+                    plural(server_info_v.len(), "session", "sessions")
+                );
+            }
+            // a query which outlived a reload removing its server has nobody to report to
+            let server_state = match state_map.get(server_handle.name()) {
+                Some(server_state) => server_state,
+                None => {
+                    warn!(
+                        "'{}' is not monitored anymore, dropping its answer",
+                        server_handle.name()
+                    );
+                    return PolledServer::failed(server_handle.name(), Vec::new());
+                }
+            };
+            let mut client_state_map = server_state.lock().unwrap();
+            if claimed.swap(true, Ordering::SeqCst) {
+                warn!(
+                    "'{}' answered after its query timed out, dropping its answer",
+                    server_handle.name()
+                );
+                return PolledServer::failed(server_handle.name(), Vec::new());
+            }
+            connection_info.extend(client_state_map.query_succeeded(server_handle.name()));
+            let mut conn_status_vec = client_state_map.update_state(&server_info_v);
+            client_state_map.summary.count(&conn_status_vec);
+            connection_info.extend(client_state_map.suppress_flapping(&mut conn_status_vec));
+            connection_info.extend(client_state_map.escalate(server_handle.name()));
+            let before = conn_status_vec.len();
+            if let Some(lost) =
+                client_state_map.coalesce_disconnects(server_handle.name(), &mut conn_status_vec)
+            {
+                metrics::DISCONNECT_EVENTS
+                    .with_label_values(&[server_handle.name()])
+                    .inc_by((before - conn_status_vec.len()) as u64);
+                connection_info.push(lost);
+            }
+            let active = client_state_map
+                .data
+                .values()
+                .filter(|d| d.state == RemoteDesktopSessionState::Active)
+                .count();
+            metrics::ACTIVE_SESSIONS
+                .with_label_values(&[server_handle.name()])
+                .set(active as i64);
+            client_state_map.record_active(active);
+            metrics::PEAK_SESSIONS
+                .with_label_values(&[server_handle.name()])
+                .set(client_state_map.peak_active as i64);
+            PolledServer {
+                server: server_handle.name().to_owned(),
+                events: connection_info,
+                sessions: conn_status_vec,
+                vip_users: client_state_map.policy.vip_users.clone(),
+                business_hours: client_state_map.policy.business_hours,
+            }
+        }
+        Err(e) => {
+            let e = NotifierError::ServerQuery {
+                server: server_handle.name().to_owned(),
+                source: e,
+            };
+            error!("{:?}", anyhow::Error::from(e));
+            debug!(
+                "'{}' did not answer, its clients keep their last known state",
+                server_handle.name()
+            );
+            // counted as failed already if the poll gave up on it
+            if !claimed.swap(true, Ordering::SeqCst) {
+                connection_info.extend(query_failed(&state_map, server_handle.name()));
+            }
+            PolledServer::failed(server_handle.name(), connection_info)
+        }
+    }
+}
+
+/// one session per client, in the order they were reported; a client may show up more than once,
+/// e.g. with a disconnected session left behind next to its new one, the one in use wins
+fn unique_sessions(client_info: &[RemoteDesktopSessionInfo]) -> Vec<&RemoteDesktopSessionInfo> {
+    let mut sessions: Vec<&RemoteDesktopSessionInfo> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for info in client_info {
+        match positions.entry(client_key(&info.client_info.client)) {
+            Entry::Vacant(e) => {
+                e.insert(sessions.len());
+                sessions.push(info);
+            }
+            Entry::Occupied(e) => {
+                let kept = &mut sessions[*e.get()];
+                if !in_session(kept.state) && in_session(info.state) {
+                    *kept = info;
+                }
+            }
+        }
+    }
+    sessions
+}
+
+/// queries of a server which fail for a moment, e.g. on a dns or rpc hiccup, are tried again
+/// within the cycle before the server counts as failed
+const QUERY_ATTEMPTS: u32 = 3;
+const QUERY_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// blocks between attempts, doubling the delay each time, jittered as every server of a cycle
+/// may fail at once
+fn query_with_retry<S: SessionSource>(source: &mut S) -> Result<Vec<RemoteDesktopSessionInfo>> {
+    let mut backoff = Backoff::new(QUERY_RETRY_DELAY, 2.0, QUERY_RETRY_DELAY * 4).full_jitter();
+    let mut attempt = 1;
+    loop {
+        metrics::QUERY_ATTEMPTS
+            .with_label_values(&[source.name()])
+            .inc();
+        match source.get_updated_info() {
+            Ok(sessions) => return Ok(sessions),
+            Err(e) if attempt < QUERY_ATTEMPTS => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "querying '{}' failed, trying again in {:?}. {:?}",
+                    source.name(),
+                    delay,
+                    e
+                );
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "gave up after {}",
+                    plural(attempt as usize, "attempt", "attempts")
+                )))
+            }
+        }
+    }
+}
+
+fn query_failed(state_map: &ServerClientMapShared, server: &str) -> Vec<Event> {
+    state_map
+        .get(server)
+        .and_then(|client_state_map| client_state_map.lock().unwrap().query_failed(server))
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdc_connections::ClientInfo;
+
+    fn session(
+        client: &str,
+        user: &str,
+        state: RemoteDesktopSessionState,
+    ) -> RemoteDesktopSessionInfo {
+        RemoteDesktopSessionInfo {
+            session_id: 1,
+            state,
+            client_info: ClientInfo {
+                user: user.to_owned(),
+                client: client.to_owned(),
+                address: (0, [0; 31]),
+            },
+        }
+    }
+
+    fn texts(events: Vec<Event>) -> Vec<String> {
+        events.into_iter().map(|e| e.text).collect()
+    }
+
+    fn disconnected(client: &str, user: &str) -> SessionEvent {
+        SessionEvent::Disconnected {
+            client: client.to_owned(),
+            user: user.to_owned(),
+            address: None,
+            from: RemoteDesktopSessionState::Active,
+            state: RemoteDesktopSessionState::Disconnected,
+            duration: None,
+        }
+    }
+
+    fn with_policy(policy: ClientPolicy) -> ClientStateMap {
+        let mut map = ClientStateMap::new();
+        map.configure(Arc::new(policy));
+        map
+    }
+
+    /// feeds scripted session lists, one per poll
+    struct MockSource {
+        name: String,
+        polls: VecDeque<Result<Vec<RemoteDesktopSessionInfo>>>,
+    }
+
+    impl SessionSource for MockSource {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn get_updated_info(&mut self) -> Result<Vec<RemoteDesktopSessionInfo>> {
+            self.polls.pop_front().unwrap_or_else(|| Ok(Vec::new()))
+        }
+    }
+
+    #[test]
+    fn first_seen_active_is_connected() {
+        let mut map = ClientStateMap::new();
+        let events = map.update_state(&[session(
+            "CLIENT01",
+            "alice",
+            RemoteDesktopSessionState::Active,
+        )]);
+        assert_eq!(
+            events,
+            vec![SessionEvent::Connected {
+                client: "CLIENT01".to_owned(),
+                user: "alice".to_owned(),
+                address: None,
+                from: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn active_to_disconnected() {
+        let mut map = ClientStateMap::new();
+        map.update_state(&[session(
+            "CLIENT01",
+            "alice",
+            RemoteDesktopSessionState::Active,
+        )]);
+        let events = map.update_state(&[session(
+            "CLIENT01",
+            "",
+            RemoteDesktopSessionState::Disconnected,
+        )]);
+        assert_eq!(events, vec![disconnected("CLIENT01", "alice")]);
+    }
+
+    #[test]
+    fn vanished_while_active_is_disconnected() {
+        let mut map = ClientStateMap::new();
+        map.update_state(&[session(
+            "CLIENT01",
+            "alice",
+            RemoteDesktopSessionState::Active,
+        )]);
+        let events = map.update_state(&[]);
+        assert_eq!(events, vec![disconnected("CLIENT01", "alice")]);
+        assert_eq!(
+            map.data["CLIENT01"].state,
+            RemoteDesktopSessionState::Disconnected
+        );
+    }
+
+    #[test]
+    fn vanished_while_already_disconnected_is_silent() {
+        let mut map = ClientStateMap::new();
+        map.update_state(&[session(
+            "CLIENT01",
+            "alice",
+            RemoteDesktopSessionState::Active,
+        )]);
+        map.update_state(&[session(
+            "CLIENT01",
+            "alice",
+            RemoteDesktopSessionState::Disconnected,
+        )]);
+        assert!(map.update_state(&[]).is_empty());
+        assert!(map.update_state(&[]).is_empty());
+    }
+
+    #[test]
+    fn mock_source_reports_server() {
+        let state_map = ServerClientMapShared::default();
+        state_map.insert("SERVER01".to_owned(), ClientStateMap::new());
+        let source = MockSource {
+            name: "SERVER01".to_owned(),
+            polls: VecDeque::from(vec![Ok(vec![session(
+                "CLIENT01",
+                "alice",
+                RemoteDesktopSessionState::Active,
+            )])]),
+        };
+        let events = read_active_connections(source, state_map, &AtomicBool::default())
+            .into_events(&MessageTemplates::default());
+        assert_eq!(
+            texts(events),
+            vec!["'CLIENT01' (user alice) is now connected to 'SERVER01'"]
+        );
+    }
+
+    #[test]
+    fn answers_of_removed_servers_are_dropped() {
+        let state_map = ServerClientMapShared::default();
+        let source = MockSource {
+            name: "SERVER02".to_owned(),
+            polls: VecDeque::from(vec![Ok(vec![session(
+                "CLIENT01",
+                "alice",
+                RemoteDesktopSessionState::Active,
+            )])]),
+        };
+        let events = read_active_connections(source, state_map.clone(), &AtomicBool::default())
+            .into_events(&MessageTemplates::default());
+        assert!(events.is_empty());
+        assert!(state_map.get("SERVER02").is_none());
+    }
+
+    #[test]
+    fn polls_do_not_wait_for_a_server_locked_elsewhere() {
+        let state_map = ServerClientMapShared::default();
+        let servers = ["SERVER05", "SERVER06", "SERVER07", "SERVER08"];
+        for server in ["SERVER04"].iter().chain(&servers) {
+            state_map.insert(server.to_string(), ClientStateMap::new());
+        }
+        let locked = state_map.get("SERVER04").unwrap();
+        let _held = locked.lock().unwrap();
+        let (done, finished) = std::sync::mpsc::channel();
+        for server in servers {
+            let (state_map, done) = (state_map.clone(), done.clone());
+            std::thread::spawn(move || {
+                let source = MockSource {
+                    name: server.to_owned(),
+                    polls: VecDeque::from(vec![Ok(vec![session(
+                        "CLIENT01",
+                        "alice",
+                        RemoteDesktopSessionState::Active,
+                    )])]),
+                };
+                let events = read_active_connections(source, state_map, &AtomicBool::default())
+                    .into_events(&MessageTemplates::default());
+                done.send((server, events.len())).unwrap();
+            });
+        }
+        for _ in servers {
+            let (server, events) = finished
+                .recv_timeout(Duration::from_secs(5))
+                .expect("a poll waited for the locked server");
+            assert_eq!(events, 1, "{}", server);
+        }
+    }
+
+    #[test]
+    fn vip_logins_are_high_severity() {
+        let map = with_policy(ClientPolicy {
+            vip_users: Some(NameFilter::new(&["admin*".to_owned()], &[])),
+            ..ClientPolicy::default()
+        });
+        let state_map = ServerClientMapShared::default();
+        state_map.insert("SERVER01".to_owned(), map);
+        let source = MockSource {
+            name: "SERVER01".to_owned(),
+            polls: VecDeque::from(vec![Ok(vec![
+                session("CLIENT01", "alice", RemoteDesktopSessionState::Active),
+                session(
+                    "CLIENT02",
+                    "administrator",
+                    RemoteDesktopSessionState::Active,
+                ),
+            ])]),
+        };
+        let templates = MessageTemplates {
+            alert_prefix: Some("@here".to_owned()),
+            ..MessageTemplates::default()
+        };
+        let events = read_active_connections(source, state_map, &AtomicBool::default())
+            .into_events(&templates);
+        let (alerts, others): (Vec<Event>, Vec<Event>) = events
+            .into_iter()
+            .partition(|e| e.severity == Severity::High);
+        assert_eq!(
+            texts(alerts),
+            vec!["@here 'CLIENT02' (user administrator) is now connected to 'SERVER01'"]
+        );
+        assert_eq!(
+            texts(others),
+            vec!["'CLIENT01' (user alice) is now connected to 'SERVER01'"]
+        );
+    }
+
+    #[test]
+    fn silent_first_poll_only_records_baseline() {
+        let mut map = with_policy(ClientPolicy {
+            silent_first_poll: true,
+            ..ClientPolicy::default()
+        });
+        let alice = session("CLIENT01", "alice", RemoteDesktopSessionState::Active);
+        assert!(map.update_state(&[alice]).is_empty());
+        assert_eq!(
+            map.update_state(&[]),
+            vec![disconnected("CLIENT01", "alice")]
+        );
+    }
+
+    #[test]
+    fn stale_clients_are_forgotten() {
+        let mut map = with_policy(ClientPolicy {
+            stale_after: Some(Duration::ZERO),
+            ..ClientPolicy::default()
+        });
+        for i in 0..100 {
+            let client = format!("CLIENT{:02}", i);
+            let active = session(&client, "alice", RemoteDesktopSessionState::Active);
+            map.update_state(&[active]);
+            map.update_state(&[]);
+            assert!(map.data.is_empty());
+        }
+        let alice = session("CLIENT00", "alice", RemoteDesktopSessionState::Active);
+        assert_eq!(map.update_state(&[alice]).len(), 1);
+    }
+
+    #[test]
+    fn opted_in_states_are_reported() {
+        let mut map = with_policy(ClientPolicy {
+            notify_states: vec![RemoteDesktopSessionState::Shadow],
+            ..ClientPolicy::default()
+        });
+        let connected = session("CLIENT01", "alice", RemoteDesktopSessionState::Connected);
+        assert!(map.update_state(&[connected]).is_empty());
+        let shadow = session("CLIENT01", "", RemoteDesktopSessionState::Shadow);
+        assert_eq!(
+            texts(
+                map.update_state(&[shadow])
+                    .iter()
+                    .map(|e| e.to_event("SERVER01", &locale::Catalog::default()))
+                    .collect()
+            ),
+            vec!["'CLIENT01' (user alice) transitioned from Connected to Shadow on 'SERVER01'"]
+        );
+        let disconnected = session("CLIENT01", "alice", RemoteDesktopSessionState::Disconnected);
+        assert!(map.update_state(&[disconnected]).is_empty());
+    }
+
+    #[test]
+    fn leaving_a_session_for_an_opted_in_state_is_a_state_change() {
+        let mut map = with_policy(ClientPolicy {
+            notify_states: vec![
+                RemoteDesktopSessionState::Shadow,
+                RemoteDesktopSessionState::Disconnected,
+            ],
+            ..ClientPolicy::default()
+        });
+        map.update_state(&[session(
+            "CLIENT01",
+            "alice",
+            RemoteDesktopSessionState::Active,
+        )]);
+        let shadow = session("CLIENT01", "alice", RemoteDesktopSessionState::Shadow);
+        assert_eq!(
+            map.update_state(&[shadow]),
+            vec![SessionEvent::StateChanged {
+                client: "CLIENT01".to_owned(),
+                user: "alice".to_owned(),
+                from: Some(RemoteDesktopSessionState::Active),
+                state: RemoteDesktopSessionState::Shadow,
+            }]
+        );
+        // disconnects are reported as such even when opted into
+        map.update_state(&[session(
+            "CLIENT01",
+            "alice",
+            RemoteDesktopSessionState::Active,
+        )]);
+        let disconnected = session("CLIENT01", "alice", RemoteDesktopSessionState::Disconnected);
+        assert!(matches!(
+            map.update_state(&[disconnected]).as_slice(),
+            [SessionEvent::Disconnected { .. }]
+        ));
+    }
+
+    #[test]
+    fn connections_carry_client_address() {
+        let mut map = ClientStateMap::new();
+        let mut alice = session("CLIENT01", "alice", RemoteDesktopSessionState::Active);
+        alice.client_info.address.0 = 2;
+        alice.client_info.address.1[..4].copy_from_slice(&[10, 2, 3, 4]);
+        let events: Vec<Event> = map
+            .update_state(&[alice])
+            .iter()
+            .map(|e| e.to_event("SERVER01", &locale::Catalog::default()))
+            .collect();
+        assert_eq!(
+            texts(events),
+            vec!["'CLIENT01' (user alice, 10.2.3.4) is now connected to 'SERVER01'"]
+        );
+    }
+
+    #[test]
+    fn mass_disconnect_is_coalesced() {
+        let mut map = with_policy(ClientPolicy {
+            coalesce_disconnects: 2,
+            ..ClientPolicy::default()
+        });
+        let clients = ["CLIENT01", "CLIENT02", "CLIENT03"];
+        let sessions: Vec<RemoteDesktopSessionInfo> = clients
+            .iter()
+            .map(|c| session(c, "alice", RemoteDesktopSessionState::Active))
+            .collect();
+        map.update_state(&sessions);
+        // a single disconnect is reported as usual
+        let mut events = map.update_state(&sessions[1..]);
+        assert!(map.coalesce_disconnects("SERVER01", &mut events).is_none());
+        assert_eq!(events, vec![disconnected("CLIENT01", "alice")]);
+        let mut events = map.update_state(&[]);
+        let lost = map.coalesce_disconnects("SERVER01", &mut events).unwrap();
+        assert!(events.is_empty());
+        assert_eq!(
+            lost.text,
+            "'SERVER01' lost 2 active sessions (server restart?)"
+        );
+    }
+
+    #[test]
+    fn users_can_be_reported_without_domain() {
+        let mut map = with_policy(ClientPolicy {
+            strip_domain: true,
+            ..ClientPolicy::default()
+        });
+        let events = map.update_state(&[session(
+            "CLIENT01",
+            "CORP\\alice",
+            RemoteDesktopSessionState::Active,
+        )]);
+        assert_eq!(events[0].user(), "alice");
+    }
+
+    #[test]
+    fn client_names_ignore_case() {
+        let mut map = ClientStateMap::new();
+        let events =
+            map.update_state(&[session("WS01", "alice", RemoteDesktopSessionState::Active)]);
+        assert_eq!(events.len(), 1);
+        let lower = session("ws01", "alice", RemoteDesktopSessionState::Active);
+        assert!(map.update_state(&[lower]).is_empty());
+        assert_eq!(map.data.len(), 1);
+        assert_eq!(map.data["WS01"].name, "WS01");
+    }
+
+    #[test]
+    fn summary_counts_polled_sessions() {
+        let state_map = ServerClientMapShared::default();
+        state_map.insert("SERVER01".to_owned(), ClientStateMap::new());
+        let alice = || session("CLIENT01", "alice", RemoteDesktopSessionState::Active);
+        let bob = session("CLIENT02", "bob", RemoteDesktopSessionState::Active);
+        for poll in [vec![alice(), bob], vec![alice()]] {
+            let source = MockSource {
+                name: "SERVER01".to_owned(),
+                polls: VecDeque::from(vec![Ok(poll)]),
+            };
+            read_active_connections(source, state_map.clone(), &AtomicBool::default())
+                .into_events(&MessageTemplates::default());
+        }
+        let server_state = state_map.get("SERVER01").unwrap();
+        let summary = &server_state.lock().unwrap().summary;
+        assert_eq!(
+            (summary.connects, summary.disconnects, summary.peak_active),
+            (2, 1, 2)
+        );
+    }
+
+    #[test]
+    fn peak_starts_over_after_reset() {
+        let mut map = ClientStateMap::new();
+        map.record_active(5);
+        map.record_active(2);
+        assert_eq!(map.peak_active, 5);
+        map.configure(Arc::new(ClientPolicy {
+            peak_reset: NaiveTime::from_hms_opt(0, 0, 0),
+            ..ClientPolicy::default()
+        }));
+        map.peak_since = Local::now() - chrono::Duration::days(1);
+        map.record_active(2);
+        assert_eq!(map.peak_active, 2);
+        assert_eq!(map.summary.peak_active, 5);
+    }
+
+    #[test]
+    fn existing_sessions_are_skipped_but_not_their_disconnects() {
+        let mut map = with_policy(ClientPolicy {
+            skip_existing: true,
+            notify_states: vec![RemoteDesktopSessionState::Disconnected],
+            ..ClientPolicy::default()
+        });
+        let alice = session("CLIENT01", "alice", RemoteDesktopSessionState::Active);
+        let bob = session("CLIENT02", "bob", RemoteDesktopSessionState::Disconnected);
+        let events = map.update_state(&[alice, bob]);
+        assert!(matches!(
+            events.as_slice(),
+            [SessionEvent::StateChanged { client, .. }] if client == "CLIENT02"
+        ));
+        assert_eq!(
+            map.update_state(&[]),
+            vec![disconnected("CLIENT01", "alice")]
+        );
+        let carol = session("CLIENT03", "carol", RemoteDesktopSessionState::Active);
+        assert!(matches!(
+            map.update_state(&[carol]).as_slice(),
+            [SessionEvent::Connected { client, .. }] if client == "CLIENT03"
+        ));
+    }
+
+    #[test]
+    fn flapping_clients_are_suppressed_until_stable() {
+        let mut map = with_policy(ClientPolicy {
+            flap_threshold: 2,
+            flap_window: Duration::from_secs(60),
+            ..ClientPolicy::default()
+        });
+        let alice = || session("CLIENT01", "alice", RemoteDesktopSessionState::Active);
+        let mut flap = |sessions: &[RemoteDesktopSessionInfo]| {
+            let mut events = map.update_state(sessions);
+            let notices = map.suppress_flapping(&mut events);
+            (events.len(), texts(notices))
+        };
+        assert_eq!(flap(&[alice()]), (1, vec![]));
+        assert_eq!(flap(&[]), (1, vec![]));
+        assert_eq!(
+            flap(&[alice()]),
+            (
+                0,
+                vec!["'CLIENT01' is flapping, notifications suppressed".to_owned()]
+            )
+        );
+        assert_eq!(flap(&[]), (0, vec![]));
+        map.configure(Arc::new(ClientPolicy {
+            flap_threshold: 2,
+            flap_window: Duration::ZERO,
+            ..ClientPolicy::default()
+        }));
+        let mut events = map.update_state(&[alice()]);
+        assert_eq!(
+            texts(map.suppress_flapping(&mut events)),
+            vec!["'CLIENT01' is stable again, notifications resumed".to_owned()]
+        );
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn long_sessions_of_escalated_users_are_reminded_of() {
+        let mut map = with_policy(ClientPolicy {
+            escalations: vec!["admin*=1h".parse().unwrap()],
+            ..ClientPolicy::default()
+        });
+        let admin = session("CLIENT01", "admin.jane", RemoteDesktopSessionState::Active);
+        let alice = session("CLIENT02", "alice", RemoteDesktopSessionState::Active);
+        map.update_state(&[]);
+        map.update_state(&[admin, alice]);
+        assert!(map.escalate("SERVER01").is_empty());
+        for data in map.data.values_mut() {
+            data.connected_at = Some(Instant::now() - Duration::from_secs(2 * 3600));
+        }
+        assert_eq!(
+            texts(map.escalate("SERVER01")),
+            vec!["'CLIENT01' (user admin.jane) is still connected to 'SERVER01' after 2h0m"]
+        );
+        // once only without a repeat
+        assert!(map.escalate("SERVER01").is_empty());
+        map.update_state(&[]);
+        assert!(map.escalate("SERVER01").is_empty());
+    }
+
+    #[test]
+    fn empty_answer_disconnects_but_failed_query_does_not() {
+        let state_map = ServerClientMapShared::default();
+        state_map.insert("SERVER01".to_owned(), ClientStateMap::new());
+        let poll = |polls: VecDeque<Result<Vec<RemoteDesktopSessionInfo>>>| {
+            let source = MockSource {
+                name: "SERVER01".to_owned(),
+                polls,
+            };
+            texts(
+                read_active_connections(source, state_map.clone(), &AtomicBool::default())
+                    .into_events(&MessageTemplates::default()),
+            )
+        };
+        poll(VecDeque::from(vec![Ok(vec![session(
+            "CLIENT01",
+            "alice",
+            RemoteDesktopSessionState::Active,
+        )])]));
+        let failed = (0..QUERY_ATTEMPTS)
+            .map(|_| Err(anyhow!("unreachable")))
+            .collect();
+        assert!(poll(failed).is_empty());
+        assert_eq!(
+            poll(VecDeque::from(vec![Ok(Vec::new())])),
+            vec!["'CLIENT01' (user alice) is disconnected from 'SERVER01'"]
+        );
+    }
+
+    #[test]
+    fn failed_queries_are_retried_within_a_cycle() {
+        let state_map = ServerClientMapShared::default();
+        state_map.insert("SERVER01".to_owned(), ClientStateMap::new());
+        let source = MockSource {
+            name: "SERVER01".to_owned(),
+            polls: VecDeque::from(vec![
+                Err(anyhow!("rpc server unavailable")),
+                Ok(vec![session(
+                    "CLIENT01",
+                    "alice",
+                    RemoteDesktopSessionState::Active,
+                )]),
+            ]),
+        };
+        let events = read_active_connections(source, state_map.clone(), &AtomicBool::default())
+            .into_events(&MessageTemplates::default());
+        assert_eq!(
+            texts(events),
+            vec!["'CLIENT01' (user alice) is now connected to 'SERVER01'"]
+        );
+        let mut source = MockSource {
+            name: "SERVER03".to_owned(),
+            polls: (0..QUERY_ATTEMPTS + 1)
+                .map(|_| Err(anyhow!("rpc server unavailable")))
+                .collect(),
+        };
+        assert!(query_with_retry(&mut source).is_err());
+        assert_eq!(source.polls.len(), 1);
+        assert_eq!(
+            metrics::QUERY_ATTEMPTS
+                .with_label_values(&["SERVER03"])
+                .get(),
+            QUERY_ATTEMPTS as u64
+        );
+    }
+
+    #[test]
+    fn first_seen_sessions_can_go_unreported() {
+        let mut map = with_policy(ClientPolicy {
+            notify_on_first_seen: false,
+            ..ClientPolicy::default()
+        });
+        let alice = || session("CLIENT01", "alice", RemoteDesktopSessionState::Active);
+        assert!(map.update_state(&[alice()]).is_empty());
+        let bob = session("CLIENT02", "bob", RemoteDesktopSessionState::Active);
+        assert!(matches!(
+            map.update_state(&[alice(), bob]).as_slice(),
+            [SessionEvent::Connected { client, .. }] if client == "CLIENT02"
+        ));
+        assert_eq!(
+            map.update_state(&[session(
+                "CLIENT02",
+                "bob",
+                RemoteDesktopSessionState::Active
+            )]),
+            vec![disconnected("CLIENT01", "alice")]
+        );
+    }
+
+    #[test]
+    fn unreachable_servers_are_alerts() {
+        let mut map = with_policy(ClientPolicy {
+            unreachable_after: 2,
+            ..ClientPolicy::default()
+        });
+        assert!(map.query_failed("SERVER01").is_none());
+        let unreachable = map.query_failed("SERVER01").unwrap();
+        assert_eq!(unreachable.severity, Severity::High);
+        assert!(is_alert(&unreachable));
+        let reachable = map.query_succeeded("SERVER01").unwrap();
+        assert!(is_alert(&reachable));
+        assert!(!is_alert(&Event::new(EventKind::Connected, "connected")));
+    }
+
+    #[test]
+    fn many_sessions_vanishing_are_each_disconnected_once() {
+        let sessions = |skip: usize| -> Vec<RemoteDesktopSessionInfo> {
+            (0..500)
+                .filter(|c| c % 7 != skip)
+                .map(|c| {
+                    session(
+                        &format!("CLIENT{:03}", c),
+                        "alice",
+                        RemoteDesktopSessionState::Active,
+                    )
+                })
+                .collect()
+        };
+        let mut map = ClientStateMap::new();
+        assert_eq!(map.update_state(&sessions(7)).len(), 500);
+        let mut events = map.update_state(&sessions(3));
+        events.sort_by(|a, b| a.client().cmp(b.client()));
+        let expected: Vec<SessionEvent> = (0..500)
+            .filter(|c| c % 7 == 3)
+            .map(|c| disconnected(&format!("CLIENT{:03}", c), "alice"))
+            .collect();
+        assert_eq!(events, expected);
+        assert!(map.update_state(&sessions(3)).is_empty());
+    }
+
+    #[test]
+    fn clients_reported_twice_get_a_single_event() {
+        let mut map = ClientStateMap::new();
+        let active = || session("CLIENT01", "alice", RemoteDesktopSessionState::Active);
+        let left_behind = || session("CLIENT01", "alice", RemoteDesktopSessionState::Disconnected);
+        assert_eq!(map.update_state(&[active(), active()]).len(), 1);
+        assert!(map.update_state(&[left_behind(), active()]).is_empty());
+        assert!(map.update_state(&[active(), left_behind()]).is_empty());
+        assert_eq!(
+            map.update_state(&[left_behind(), left_behind()]),
+            vec![disconnected("CLIENT01", "alice")]
+        );
+    }
+
+    /// answers with a single active session, once `delay` has passed
+    struct SlowSource {
+        name: String,
+        delay: Duration,
+    }
+
+    impl SessionSource for SlowSource {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn get_updated_info(&mut self) -> Result<Vec<RemoteDesktopSessionInfo>> {
+            std::thread::sleep(self.delay);
+            Ok(vec![session(
+                "CLIENT01",
+                "alice",
+                RemoteDesktopSessionState::Active,
+            )])
+        }
+    }
+
+    /// polls which post nowhere, as the webhook is a dry run
+    fn poll_context(
+        state_map: &ServerClientMapShared,
+        query_timeout: Duration,
+        max_concurrent: Option<usize>,
+    ) -> PollContext {
+        let input = UserInput {
+            url: "https://example.com/hook".to_owned(),
+            dry_run: true,
+            ..UserInput::default()
+        };
+        let msg_sender: MsgSender = Arc::new(WebhookRouter::new(&input).unwrap());
+        let outbox = Outbox::load(None, Duration::ZERO, DeadLetters::new(None));
+        let (queue, _) = delivery::spawn_delivery_worker(
+            16,
+            delivery::QueueFull::Wait,
+            delivery::FailurePolicy::Drop,
+            msg_sender.clone(),
+            outbox,
+        );
+        PollContext {
+            msg_sender,
+            state_map: state_map.clone(),
+            templates: Templates::default(),
+            resolver: None,
+            quiet: Arc::new(QuietGate::new(None, false)),
+            mutes: MutesShared::default(),
+            feed: EventFeed {
+                live: broadcast::channel(16).0,
+                notifiers: None,
+            },
+            queue,
+            query_timeout: Some(query_timeout),
+            max_concurrent,
+            batch: false,
+            shutdown: watch::channel(false).1,
+        }
+    }
+
+    #[tokio::test]
+    async fn hung_servers_do_not_hold_up_the_others_at_the_limit() {
+        let state_map = ServerClientMapShared::default();
+        for server in ["SERVER09", "SERVER10"] {
+            state_map.insert(server.to_owned(), ClientStateMap::new());
+        }
+        let ctx = poll_context(&state_map, Duration::from_millis(100), Some(1));
+        let servers = vec!["SERVER09".to_owned(), "SERVER10".to_owned()];
+        let refresh = refresh_all_connections(ctx, servers, |name| SlowSource {
+            name: name.to_owned(),
+            delay: if name == "SERVER09" {
+                Duration::from_secs(1)
+            } else {
+                Duration::ZERO
+            },
+        });
+        timeout(Duration::from_millis(800), refresh)
+            .await
+            .expect("the poll cycle waited for the hung server");
+        let polled = |server: &str| {
+            let state = state_map.get(server).unwrap();
+            let state = state.lock().unwrap();
+            (state.failures, state.data.len())
+        };
+        assert_eq!(polled("SERVER09"), (1, 0));
+        assert_eq!(polled("SERVER10"), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn answers_after_a_timeout_are_dropped() {
+        let state_map = ServerClientMapShared::default();
+        state_map.insert("SERVER11".to_owned(), ClientStateMap::new());
+        let ctx = poll_context(&state_map, Duration::from_millis(50), None);
+        refresh_all_connections(ctx, vec!["SERVER11".to_owned()], |name| SlowSource {
+            name: name.to_owned(),
+            delay: Duration::from_millis(300),
+        })
+        .await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let state = state_map.get("SERVER11").unwrap();
+        let state = state.lock().unwrap();
+        assert_eq!(state.failures, 1);
+        // reported by the next poll, as it was never recorded
+        assert!(state.data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn work_is_given_up_on_after_the_shutdown_timeout() {
+        let (tx, mut shutdown) = watch::channel(false);
+        let timeout = Duration::from_millis(50);
+        let mut give_up_at = None;
+        let done = finish_or_give_up(async { 1 }, &mut shutdown, timeout, &mut give_up_at).await;
+        assert_eq!(done, Some(1));
+        assert!(give_up_at.is_none());
+        tx.send(true).unwrap();
+        let hung = finish_or_give_up(
+            future::pending::<()>(),
+            &mut shutdown,
+            timeout,
+            &mut give_up_at,
+        );
+        assert_eq!(hung.await, None);
+        // whatever comes after shares the same deadline
+        let deadline = give_up_at.unwrap();
+        assert!(deadline <= Instant::now());
+        let next = finish_or_give_up(
+            future::pending::<()>(),
+            &mut shutdown,
+            timeout,
+            &mut give_up_at,
+        );
+        assert_eq!(next.await, None);
+        assert_eq!(give_up_at, Some(deadline));
+    }
+}
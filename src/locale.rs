@@ -0,0 +1,133 @@
+use crate::format::fill;
+use anyhow::{anyhow, Result};
+use std::{collections::HashMap, fs, path::Path};
+
+/// built-in texts, placeholders are `{label}` (client with user and address), `{client}`,
+/// `{user}`, `{server}`, `{from}`, `{to}`, `{state}` and `{duration}`
+const ENGLISH: [(&str, &str); 7] = [
+    ("user_label", "user {user}"),
+    ("connected", "{label} is now connected to '{server}'"),
+    ("disconnected", "{label} is disconnected from '{server}'"),
+    (
+        "disconnected_after",
+        "{label} is disconnected after {duration} from '{server}'",
+    ),
+    (
+        "user_changed",
+        "'{client}' user changed from {from} to {to} on '{server}'",
+    ),
+    ("state_changed", "{label} is now {state} on '{server}'"),
+    (
+        "state_changed_from",
+        "{label} transitioned from {from} to {state} on '{server}'",
+    ),
+];
+
+/// texts of session events by key, english unless loaded from a locale file
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    messages: HashMap<String, String>,
+    /// keys the locale file lacks, which keep their english text
+    lacking: Vec<&'static str>,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self {
+            messages: ENGLISH
+                .iter()
+                .map(|(key, text)| (key.to_string(), text.to_string()))
+                .collect(),
+            lacking: Vec::new(),
+        }
+    }
+}
+
+impl Catalog {
+    /// toml file of `key = "text"` entries, keys it lacks keep their english text; an unknown
+    /// key is an error, as it is likely a misspelled one. Loaded before logging is set up, so
+    /// what it lacks is left to be told by `lacking`
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("locale file {:?} could not be read. {:?}", path, e))?;
+        let loaded: HashMap<String, String> = toml::from_str(&content)
+            .map_err(|e| anyhow!("locale file {:?} is invalid. {}", path, e))?;
+        let mut catalog = Self::default();
+        if let Some(key) = loaded.keys().find(|k| !catalog.messages.contains_key(*k)) {
+            return Err(anyhow!(
+                "locale file {:?} has an unknown key '{}'",
+                path,
+                key
+            ));
+        }
+        catalog.lacking = ENGLISH
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|key| !loaded.contains_key(*key))
+            .collect();
+        catalog.messages.extend(loaded);
+        Ok(catalog)
+    }
+
+    pub fn lacking(&self) -> &[&'static str] {
+        &self.lacking
+    }
+
+    /// the text of `key` with `{name}` placeholders replaced by their values
+    pub fn text(&self, key: &str, values: &[(&str, &str)]) -> String {
+        let template = self.messages.get(key).map(String::as_str).unwrap_or(key);
+        fill(template, values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::SessionEvent;
+
+    #[test]
+    fn locale_texts_fall_back_to_english() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("locale.toml");
+        std::fs::write(
+            &path,
+            "connected = \"{label} ist jetzt mit '{server}' verbunden\"\nuser_label = \"Benutzer {user}\"\n",
+        )
+        .unwrap();
+        let catalog = Catalog::load(&path).unwrap();
+        let connected = SessionEvent::Connected {
+            client: "PC-01".to_owned(),
+            user: "alice".to_owned(),
+            address: None,
+            from: None,
+        };
+        assert_eq!(
+            connected.to_event("SERVER01", &catalog).text,
+            "'PC-01' (Benutzer alice) ist jetzt mit 'SERVER01' verbunden"
+        );
+        let user_changed = SessionEvent::UserChanged {
+            client: "PC-01".to_owned(),
+            from: "alice".to_owned(),
+            to: "bob".to_owned(),
+        };
+        assert_eq!(
+            user_changed.to_event("SERVER01", &catalog).text,
+            "'PC-01' user changed from alice to bob on 'SERVER01'"
+        );
+        assert_eq!(catalog.lacking().len(), ENGLISH.len() - 2);
+        assert!(catalog.lacking().contains(&"user_changed"));
+    }
+
+    #[test]
+    fn unknown_locale_keys_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("locale.toml");
+        std::fs::write(&path, "conected = \"{label} ist verbunden\"\n").unwrap();
+        let error = Catalog::load(&path).unwrap_err().to_string();
+        assert!(
+            error.ends_with("has an unknown key 'conected'"),
+            "{}",
+            error
+        );
+    }
+}
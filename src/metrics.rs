@@ -0,0 +1,86 @@
+use lazy_static::lazy_static;
+use log::error;
+use prometheus::{
+    register_gauge, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Gauge, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref ACTIVE_SESSIONS: IntGaugeVec = register_int_gauge_vec!(
+        "rdc_active_sessions",
+        "number of active rdc sessions",
+        &["server"]
+    )
+    .unwrap();
+    pub static ref PEAK_SESSIONS: IntGaugeVec = register_int_gauge_vec!(
+        "rdc_peak_active_sessions",
+        "most active rdc sessions at once since start or the last peak reset",
+        &["server"]
+    )
+    .unwrap();
+    pub static ref CONNECT_EVENTS: IntCounterVec = register_int_counter_vec!(
+        "rdc_connect_events_total",
+        "number of sessions seen connecting",
+        &["server"]
+    )
+    .unwrap();
+    pub static ref DISCONNECT_EVENTS: IntCounterVec = register_int_counter_vec!(
+        "rdc_disconnect_events_total",
+        "number of sessions seen disconnecting",
+        &["server"]
+    )
+    .unwrap();
+    pub static ref WEBHOOK_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "rdc_webhook_failures_total",
+        "number of webhook posts given up on",
+        &["server"]
+    )
+    .unwrap();
+    pub static ref WEBHOOK_CIRCUIT: IntGaugeVec = register_int_gauge_vec!(
+        "rdc_webhook_circuit_state",
+        "circuit breaker of a webhook, 0 closed, 1 half-open, 2 open",
+        &["webhook"]
+    )
+    .unwrap();
+    pub static ref QUERY_ATTEMPTS: IntCounterVec = register_int_counter_vec!(
+        "rdc_server_query_attempts_total",
+        "number of attempts to query a server, retries within a cycle included",
+        &["server"]
+    )
+    .unwrap();
+    pub static ref POLL_DURATION: HistogramVec = register_histogram_vec!(
+        "rdc_poll_duration_seconds",
+        "time taken to query a server for its sessions",
+        &["server"]
+    )
+    .unwrap();
+    pub static ref QUERIES_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "rdc_server_queries_in_flight",
+        "number of server queries running, including those given up on but not returned yet"
+    )
+    .unwrap();
+    pub static ref CYCLE_DURATION: Gauge = register_gauge!(
+        "rdc_poll_cycle_duration_seconds",
+        "time taken by the last poll cycle, including webhook posts"
+    )
+    .unwrap();
+    pub static ref SLOW_CYCLES: IntCounter = register_int_counter!(
+        "rdc_slow_poll_cycles_total",
+        "number of poll cycles which took longer than the period of their servers"
+    )
+    .unwrap();
+}
+
+/// renders all registered metrics in prometheus text format
+pub fn gather() -> (String, String) {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap_or_else(|e| error!("metrics could not be encoded. {:?}", e));
+    (
+        encoder.format_type().to_owned(),
+        String::from_utf8_lossy(&buffer).into_owned(),
+    )
+}
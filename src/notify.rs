@@ -0,0 +1,365 @@
+use crate::format::{Event, EventKind, Severity};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::error;
+use reqwest::Url;
+use rusqlite::{params, Connection};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+/// somewhere session events are sent besides the webhook, which has a queue of its own
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// for logs
+    fn name(&self) -> &str;
+
+    async fn notify(&self, event: &Event) -> Result<()>;
+}
+
+/// runs a blocking write to disk without holding up a runtime worker
+async fn write_blocking<T: Send + 'static>(
+    target: &Arc<Mutex<T>>,
+    event: &Event,
+    write: fn(&mut T, &Event) -> Result<()>,
+) -> Result<()> {
+    let (target, event) = (target.clone(), event.clone());
+    tokio::task::spawn_blocking(move || write(&mut target.lock().unwrap(), &event)).await?
+}
+
+/// one row per session event in the `events` table
+pub struct SqliteNotifier {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteNotifier {
+    /// creates the database and its table when missing
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .map_err(|e| anyhow!("database {:?} could not be opened. {}", path, e))?;
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS events (
+                    at TEXT NOT NULL,
+                    server TEXT NOT NULL,
+                    client TEXT NOT NULL,
+                    user TEXT NOT NULL,
+                    address TEXT NOT NULL,
+                    event TEXT NOT NULL,
+                    state TEXT NOT NULL,
+                    duration_secs INTEGER
+                )",
+                [],
+            )
+            .map_err(|e| anyhow!("events table of {:?} could not be created. {}", path, e))?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    fn insert(connection: &mut Connection, event: &Event) -> Result<()> {
+        let session = match &event.session {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        connection.execute(
+            "INSERT INTO events (at, server, client, user, address, event, state, duration_secs)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                event.timestamp(),
+                session.server,
+                session.client,
+                session.user,
+                session.address,
+                format!("{:?}", event.kind),
+                session.state,
+                session.duration.map(|d| d.as_secs() as i64),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for SqliteNotifier {
+    fn name(&self) -> &str {
+        "sqlite"
+    }
+
+    async fn notify(&self, event: &Event) -> Result<()> {
+        write_blocking(&self.connection, event, Self::insert).await
+    }
+}
+
+/// one line per session event, appended to a file starting with a header row
+pub struct CsvNotifier {
+    file: Arc<Mutex<File>>,
+}
+
+impl CsvNotifier {
+    const HEADER: [&'static str; 6] = [
+        "timestamp",
+        "server",
+        "client",
+        "user",
+        "event_type",
+        "duration",
+    ];
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("csv file {:?} could not be opened. {}", path, e))?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(csv_line(&Self::HEADER).as_bytes())?;
+        }
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    fn append(file: &mut File, event: &Event) -> Result<()> {
+        let session = match &event.session {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let kind = format!("{:?}", event.kind);
+        let duration = session
+            .duration
+            .map(|d| d.as_secs().to_string())
+            .unwrap_or_default();
+        let line = csv_line(&[
+            &event.timestamp(),
+            &session.server,
+            &session.client,
+            &session.user,
+            &kind,
+            &duration,
+        ]);
+        // a single write per line, so lines of concurrent writers do not interleave
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for CsvNotifier {
+    fn name(&self) -> &str {
+        "csv"
+    }
+
+    async fn notify(&self, event: &Event) -> Result<()> {
+        write_blocking(&self.file, event, Self::append).await
+    }
+}
+
+/// quotes fields which would otherwise be split or misread, e.g. `CORP\alice` or `Doe, Jane`
+pub fn csv_line(fields: &[&str]) -> String {
+    let quoted: Vec<String> = fields
+        .iter()
+        .map(|field| {
+            if field.contains([',', '"', '\\', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                (*field).to_owned()
+            }
+        })
+        .collect();
+    format!("{}\r\n", quoted.join(","))
+}
+
+type SyslogLogger = syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>;
+
+/// one message per session event, to the local syslog or a remote one
+pub struct SyslogNotifier {
+    logger: Arc<Mutex<SyslogLogger>>,
+}
+
+impl SyslogNotifier {
+    /// `target` is `unix:` for the local syslog, `unix:///path/to/socket`, `udp://host[:514]` or
+    /// `tcp://host[:601]`
+    pub fn connect(target: &str) -> Result<Self> {
+        let url = Url::parse(target)
+            .map_err(|e| anyhow!("syslog target '{}' is invalid. {}", target, e))?;
+        let server = |default_port: u16| match url.host_str() {
+            Some(host) => Ok(format!("{}:{}", host, url.port().unwrap_or(default_port))),
+            None => Err(anyhow!("syslog target '{}' has no host", target)),
+        };
+        let formatter = syslog::Formatter3164 {
+            facility: syslog::Facility::LOG_AUTH,
+            hostname: None,
+            process: env!("CARGO_PKG_NAME").to_owned(),
+            pid: std::process::id(),
+        };
+        let logger = match url.scheme() {
+            "unix" if url.path().is_empty() => syslog::unix(formatter),
+            "unix" => syslog::unix_custom(formatter, url.path()),
+            "udp" => syslog::udp(formatter, "0.0.0.0:0".to_owned(), server(514)?),
+            "tcp" => syslog::tcp(formatter, server(601)?),
+            scheme => {
+                return Err(anyhow!(
+                    "syslog target scheme '{}' is not one of unix, udp or tcp",
+                    scheme
+                ))
+            }
+        }
+        .map_err(|e| anyhow!("syslog at '{}' could not be reached. {}", target, e))?;
+        Ok(Self {
+            logger: Arc::new(Mutex::new(logger)),
+        })
+    }
+
+    fn send(logger: &mut SyslogLogger, event: &Event) -> Result<()> {
+        let session = match &event.session {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let message = format!(
+            "event={:?} server={:?} client={:?} user={:?} address={:?} state={:?} {}",
+            event.kind,
+            session.server,
+            session.client,
+            session.user,
+            session.address,
+            session.state,
+            event.text
+        );
+        match (event.severity, event.kind) {
+            (Severity::High, _) => logger.warning(message),
+            (_, EventKind::Connected | EventKind::Disconnected | EventKind::UserChanged) => {
+                logger.notice(message)
+            }
+            _ => logger.info(message),
+        }
+        .map_err(|e| anyhow!("syslog message could not be sent. {}", e))?;
+        // tcp messages are buffered otherwise
+        logger.backend.flush()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for SyslogNotifier {
+    fn name(&self) -> &str {
+        "syslog"
+    }
+
+    async fn notify(&self, event: &Event) -> Result<()> {
+        write_blocking(&self.logger, event, Self::send).await
+    }
+}
+
+/// writes session events to the application event log, high severity ones as warnings; the
+/// event kind is the category
+#[cfg(windows)]
+pub struct EventLogNotifier {
+    logger: winlog::WinLogger,
+}
+
+#[cfg(windows)]
+impl EventLogNotifier {
+    pub fn register() -> Result<Self> {
+        let source = env!("CARGO_PKG_NAME");
+        // needs administrator rights, which are not needed again once the source is registered
+        if let Err(e) = winlog::try_register(source) {
+            log::warn!(
+                "event log source '{}' could not be registered. {}",
+                source,
+                e
+            );
+        }
+        let logger = winlog::WinLogger::try_new(source)
+            .map_err(|e| anyhow!("event log source '{}' could not be opened. {}", source, e))?;
+        Ok(Self { logger })
+    }
+}
+
+#[cfg(windows)]
+#[async_trait]
+impl Notifier for EventLogNotifier {
+    fn name(&self) -> &str {
+        "eventlog"
+    }
+
+    async fn notify(&self, event: &Event) -> Result<()> {
+        use log::Log;
+        let level = match event.severity {
+            Severity::High => log::Level::Warn,
+            Severity::Normal => log::Level::Info,
+        };
+        self.logger.log(
+            &log::Record::builder()
+                .level(level)
+                .target(env!("CARGO_PKG_NAME"))
+                .args(format_args!(
+                    "category: {:?}\n{}",
+                    event.kind,
+                    event.marked_text(None)
+                ))
+                .build(),
+        );
+        Ok(())
+    }
+}
+
+/// hands events to the notifier task, never blocking the poll
+#[derive(Clone)]
+pub struct Notifiers {
+    tx: mpsc::UnboundedSender<Event>,
+}
+
+impl Notifiers {
+    pub fn notify(&self, event: Event) {
+        if self.tx.send(event).is_err() {
+            error!("notifier task is gone, events are lost");
+        }
+    }
+}
+
+/// the task fans every event out to all notifiers at once, one failing does not keep the event
+/// from the others; it runs until each `Notifiers` is dropped and the queue drained
+pub fn spawn_notifiers(notifiers: Vec<Arc<dyn Notifier>>) -> (Notifiers, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+    let task = tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            let event = Arc::new(event);
+            let tasks: Vec<_> = notifiers
+                .iter()
+                .map(|notifier| {
+                    let (notifier, event) = (notifier.clone(), event.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = notifier.notify(&event).await {
+                            error!("{} notifier failed. {:?}", notifier.name(), e);
+                        }
+                    })
+                })
+                .collect();
+            // events keep their order within each notifier
+            for task in tasks {
+                if let Err(e) = task.await {
+                    error!("notifier task failed. {:?}", e);
+                }
+            }
+        }
+    });
+    (Notifiers { tx }, task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        assert_eq!(
+            csv_line(&["CORP\\alice", "Doe, Jane", "say \"hi\"", "plain"]),
+            "\"CORP\\alice\",\"Doe, Jane\",\"say \"\"hi\"\"\",plain\r\n"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{client_key, ClientData, ClientStateMap, ServerClientMap, ServerStates};
+use anyhow::{anyhow, Result};
+use log::info;
+use rdc_connections::RemoteDesktopSessionState;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    net::IpAddr,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{Duration, Instant};
+
+/// on-disk mirror of `ServerClientMap`, `Instant`s are stored as unix seconds
+type StoredMap = HashMap<String, HashMap<String, StoredClient>>;
+
+#[derive(Serialize, Deserialize)]
+struct StoredClient {
+    state: StoredState,
+    user: String,
+    connected_at: Option<u64>,
+    /// missing in files written before addresses were kept
+    #[serde(default)]
+    address: Option<IpAddr>,
+}
+
+/// serde mirror of `RemoteDesktopSessionState`
+#[derive(Serialize, Deserialize)]
+enum StoredState {
+    Active,
+    Connected,
+    ConnectQuery,
+    Shadow,
+    Disconnected,
+    Idle,
+    Listen,
+    Reset,
+    Down,
+    Init,
+}
+
+impl From<RemoteDesktopSessionState> for StoredState {
+    fn from(state: RemoteDesktopSessionState) -> Self {
+        match state {
+            RemoteDesktopSessionState::Active => Self::Active,
+            RemoteDesktopSessionState::Connected => Self::Connected,
+            RemoteDesktopSessionState::ConnectQuery => Self::ConnectQuery,
+            RemoteDesktopSessionState::Shadow => Self::Shadow,
+            RemoteDesktopSessionState::Disconnected => Self::Disconnected,
+            RemoteDesktopSessionState::Idle => Self::Idle,
+            RemoteDesktopSessionState::Listen => Self::Listen,
+            RemoteDesktopSessionState::Reset => Self::Reset,
+            RemoteDesktopSessionState::Down => Self::Down,
+            RemoteDesktopSessionState::Init => Self::Init,
+        }
+    }
+}
+
+impl From<StoredState> for RemoteDesktopSessionState {
+    fn from(state: StoredState) -> Self {
+        match state {
+            StoredState::Active => Self::Active,
+            StoredState::Connected => Self::Connected,
+            StoredState::ConnectQuery => Self::ConnectQuery,
+            StoredState::Shadow => Self::Shadow,
+            StoredState::Disconnected => Self::Disconnected,
+            StoredState::Idle => Self::Idle,
+            StoredState::Listen => Self::Listen,
+            StoredState::Reset => Self::Reset,
+            StoredState::Down => Self::Down,
+            StoredState::Init => Self::Init,
+        }
+    }
+}
+
+pub fn save_state(path: &Path, state_map: &ServerStates) -> Result<()> {
+    let now = (Instant::now(), SystemTime::now());
+    let stored: StoredMap = state_map
+        .snapshot()
+        .into_iter()
+        .map(|(server, state)| {
+            let clients = state
+                .lock()
+                .unwrap()
+                .data
+                .values()
+                .map(|data| {
+                    let stored_client = StoredClient {
+                        state: data.state.into(),
+                        user: data.user.clone(),
+                        connected_at: data.connected_at.map(|at| to_unix_secs(at, now)),
+                        address: data.address,
+                    };
+                    (data.name.clone(), stored_client)
+                })
+                .collect();
+            (server, clients)
+        })
+        .collect();
+    let content = serde_json::to_string_pretty(&stored)?;
+    // write aside and rename, so a crash never leaves a half written file behind
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)
+        .map_err(|e| anyhow!("state file {:?} could not be written. {:?}", tmp_path, e))?;
+    fs::rename(&tmp_path, path)
+        .map_err(|e| anyhow!("state file {:?} could not be replaced. {:?}", path, e))
+}
+
+/// a missing file is not an error, it just means there is nothing to restore yet
+pub fn load_state(path: &Path) -> Result<ServerClientMap> {
+    if !path.exists() {
+        info!("state file {:?} does not exist yet", path);
+        return Ok(HashMap::new());
+    }
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow!("state file {:?} could not be read. {:?}", path, e))?;
+    let stored: StoredMap = serde_json::from_str(&content)
+        .map_err(|e| anyhow!("state file {:?} is invalid. {}", path, e))?;
+    let now = (Instant::now(), SystemTime::now());
+    Ok(stored
+        .into_iter()
+        .map(|(server, clients)| {
+            let data = clients
+                .into_iter()
+                .map(|(client, stored_client)| {
+                    let data = ClientData {
+                        name: client.clone(),
+                        state: stored_client.state.into(),
+                        user: stored_client.user,
+                        connected_at: stored_client.connected_at.map(|s| from_unix_secs(s, now)),
+                        pending_since: None,
+                        inactive_since: None,
+                        address: stored_client.address,
+                        flaps: VecDeque::new(),
+                        flapping: false,
+                        reminded: None,
+                    };
+                    (client_key(&client), data)
+                })
+                .collect();
+            // restored sessions are known, so they must not be reported again
+            let client_state_map = ClientStateMap {
+                data,
+                baseline_done: true,
+                ..ClientStateMap::new()
+            };
+            (server, client_state_map)
+        })
+        .collect())
+}
+
+fn to_unix_secs(at: Instant, now: (Instant, SystemTime)) -> u64 {
+    let wall_clock = now.1 - now.0.duration_since(at);
+    wall_clock
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn from_unix_secs(secs: u64, now: (Instant, SystemTime)) -> Instant {
+    let elapsed = now
+        .1
+        .duration_since(UNIX_EPOCH + Duration::from_secs(secs))
+        .unwrap_or_default();
+    now.0.checked_sub(elapsed).unwrap_or(now.0)
+}
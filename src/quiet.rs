@@ -0,0 +1,217 @@
+use crate::format::{plural, Event, EventKind};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, Weekday};
+use serde::{Deserialize, Deserializer};
+use std::{str::FromStr, sync::Mutex};
+
+/// daily window like `22:00-06:00`, which may cross midnight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = parse_window(s, "quiet hours")?;
+        Ok(Self { start, end })
+    }
+}
+
+fn parse_window(s: &str, what: &str) -> Result<(NaiveTime, NaiveTime)> {
+    let parse = |t: &str| {
+        NaiveTime::parse_from_str(t.trim(), "%H:%M")
+            .map_err(|e| anyhow!("{} '{}' are invalid. {}", what, s, e))
+    };
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| anyhow!("{} '{}' are not in 'HH:MM-HH:MM' form", what, s))?;
+    Ok((parse(start)?, parse(end)?))
+}
+
+impl<'de> Deserialize<'de> for QuietHours {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// working days and daily hours like `mon-fri 08:00-18:00`, days may also be listed as
+/// `mon,wed,fri` and default to monday to friday; hours crossing midnight belong to the day
+/// they start on
+#[derive(Debug, Clone, Copy)]
+pub struct BusinessHours {
+    /// indexed by days from monday
+    days: [bool; 7],
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl BusinessHours {
+    pub fn contains(&self, at: DateTime<Local>) -> bool {
+        let (time, day) = (at.time(), at.weekday());
+        let is_workday = |day: Weekday| self.days[day.num_days_from_monday() as usize];
+        if self.start <= self.end {
+            is_workday(day) && self.start <= time && time < self.end
+        } else {
+            (is_workday(day) && time >= self.start)
+                || (is_workday((at - Duration::days(1)).weekday()) && time < self.end)
+        }
+    }
+}
+
+impl FromStr for BusinessHours {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (days, hours) = match s.trim().split_once(' ') {
+            Some((days, hours)) => (days, hours),
+            None => ("mon-fri", s),
+        };
+        let weekday = |d: &str| {
+            d.trim()
+                .parse::<Weekday>()
+                .map_err(|_| anyhow!("business hours '{}' have an unknown day '{}'", s, d))
+        };
+        let mut workdays = [false; 7];
+        for part in days.split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (weekday(first)?, weekday(last)?),
+                None => (weekday(part)?, weekday(part)?),
+            };
+            let mut day = first;
+            workdays[day.num_days_from_monday() as usize] = true;
+            while day != last {
+                day = day.succ();
+                workdays[day.num_days_from_monday() as usize] = true;
+            }
+        }
+        let (start, end) = parse_window(hours, "business hours")?;
+        Ok(Self {
+            days: workdays,
+            start,
+            end,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for BusinessHours {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// holds back events during quiet hours, optionally to be summarized once they are over
+pub struct QuietGate {
+    hours: Option<QuietHours>,
+    summary: bool,
+    held: Mutex<Vec<Event>>,
+}
+
+impl QuietGate {
+    pub fn new(hours: Option<QuietHours>, summary: bool) -> Self {
+        Self {
+            hours,
+            summary,
+            held: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// with other hours, events held back so far are kept for the summary
+    pub fn reconfigured(&self, hours: Option<QuietHours>, summary: bool) -> Self {
+        Self {
+            hours,
+            summary,
+            held: Mutex::new(std::mem::take(&mut *self.held.lock().unwrap())),
+        }
+    }
+
+    fn is_quiet(&self) -> bool {
+        self.hours.is_some_and(|h| h.contains(Local::now().time()))
+    }
+
+    /// returns false when events must not be posted now, they are kept for the summary instead
+    pub fn pass(&self, events: &[Event]) -> bool {
+        if !self.is_quiet() {
+            return true;
+        }
+        if self.summary {
+            self.held.lock().unwrap().extend_from_slice(events);
+        }
+        false
+    }
+
+    /// everything held back, once quiet hours are over
+    pub fn take_summary(&self) -> Option<Event> {
+        if self.is_quiet() {
+            return None;
+        }
+        let held = std::mem::take(&mut *self.held.lock().unwrap());
+        if held.is_empty() {
+            return None;
+        }
+        let lines: Vec<String> = held
+            .iter()
+            .map(|e| format!("[{}] {}", e.timestamp(), e.text))
+            .collect();
+        Some(Event::new(
+            EventKind::Info,
+            format!(
+                "quiet hours are over, {} meanwhile:\n{}",
+                plural(held.len(), "event happened", "events happened"),
+                lines.join("\n")
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_hours_cross_midnight() {
+        let at = |h, m| chrono::NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let night: QuietHours = "22:00-06:00".parse().unwrap();
+        assert!(night.contains(at(23, 30)));
+        assert!(night.contains(at(2, 0)));
+        assert!(!night.contains(at(6, 0)));
+        assert!(!night.contains(at(12, 0)));
+        let lunch: QuietHours = "12:00-13:00".parse().unwrap();
+        assert!(lunch.contains(at(12, 30)));
+        assert!(!lunch.contains(at(23, 0)));
+        assert!("22:00".parse::<QuietHours>().is_err());
+    }
+
+    #[test]
+    fn business_hours_skip_weekends() {
+        use chrono::TimeZone;
+        // 2024-01-05 is a friday
+        let at = |d, h| chrono::Local.ymd(2024, 1, d).and_hms(h, 0, 0);
+        let office: BusinessHours = "mon-fri 08:00-18:00".parse().unwrap();
+        assert!(office.contains(at(5, 9)));
+        assert!(!office.contains(at(5, 3)));
+        assert!(!office.contains(at(6, 9)));
+        let nights: BusinessHours = "fri 22:00-06:00".parse().unwrap();
+        assert!(nights.contains(at(5, 23)));
+        assert!(nights.contains(at(6, 2)));
+        assert!(!nights.contains(at(7, 2)));
+        assert!("mon-fri".parse::<BusinessHours>().is_err());
+        assert!("someday 08:00-18:00".parse::<BusinessHours>().is_err());
+    }
+}
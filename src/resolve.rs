@@ -0,0 +1,95 @@
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::OnceCell,
+    time::{timeout, Duration, Instant},
+};
+
+/// a lookup of an address, shared by everyone asking for it meanwhile
+type Lookup = Arc<OnceCell<Option<String>>>;
+
+/// reverse dns names of client addresses, failed lookups are cached as well so an address
+/// without a name is not looked up again every poll
+pub struct Resolver {
+    cache: Mutex<HashMap<IpAddr, (Lookup, Instant)>>,
+}
+
+impl Resolver {
+    const TTL: Duration = Duration::from_secs(60 * 60);
+    /// beyond which the bare address is reported rather than holding up the events
+    const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+    pub fn new() -> Self {
+        Self {
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// the lookup runs on the blocking pool, lookups which time out are not cached
+    pub async fn name(&self, ip: IpAddr) -> Option<String> {
+        let lookup = {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get(&ip) {
+                Some((lookup, at)) if at.elapsed() < Self::TTL => lookup.clone(),
+                _ => {
+                    let lookup = Lookup::default();
+                    cache.insert(ip, (lookup.clone(), Instant::now()));
+                    lookup
+                }
+            }
+        };
+        let looked_up = timeout(
+            Self::LOOKUP_TIMEOUT,
+            lookup.get_or_init(|| async move {
+                let name = tokio::task::spawn_blocking(move || dns_lookup::lookup_addr(&ip)).await;
+                match name {
+                    // addresses without a name may resolve to themselves
+                    Ok(Ok(name)) if name != ip.to_string() => Some(name),
+                    Ok(Ok(_)) => None,
+                    Ok(Err(e)) => {
+                        debug!("{} could not be resolved. {:?}", ip, e);
+                        None
+                    }
+                    Err(e) => {
+                        warn!("lookup of {} failed. {:?}", ip, e);
+                        None
+                    }
+                }
+            }),
+        )
+        .await;
+        match looked_up {
+            Ok(name) => name.clone(),
+            Err(_) => {
+                debug!("lookup of {} timed out, keeping the bare address", ip);
+                let mut cache = self.cache.lock().unwrap();
+                if cache.get(&ip).is_some_and(|(cached, _)| {
+                    Arc::ptr_eq(cached, &lookup) && !cached.initialized()
+                }) {
+                    cache.remove(&ip);
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_lookups_of_an_address_share_one() {
+        let resolver = Resolver::new();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let (first, second) = tokio::join!(resolver.name(ip), resolver.name(ip));
+        assert_eq!(first, second);
+        let cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache[&ip].0.initialized());
+    }
+}
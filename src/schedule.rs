@@ -0,0 +1,187 @@
+use crate::config::UserInput;
+use log::info;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use tokio::time::{Duration, Instant};
+
+/// periods of servers without their own, depending on whether any session is open
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptivePeriods {
+    pub active: Duration,
+    pub idle: Duration,
+}
+
+/// keeps track of when each server is due for its next poll
+#[derive(Debug)]
+pub struct PollSchedule {
+    periods: HashMap<String, Duration>,
+    next_poll: HashMap<String, Instant>,
+    adaptive: Option<AdaptivePeriods>,
+    /// servers with a period of their own, which adaptive polling leaves alone
+    fixed: HashSet<String>,
+    /// whether any session was open at the last poll
+    busy: bool,
+    /// in percent, spreads the polls of instances started together
+    jitter: u32,
+}
+
+impl PollSchedule {
+    pub fn new(input: &UserInput) -> Self {
+        let mut schedule = Self {
+            periods: HashMap::new(),
+            next_poll: HashMap::new(),
+            adaptive: None,
+            fixed: HashSet::new(),
+            busy: false,
+            jitter: 0,
+        };
+        schedule.reload(input);
+        schedule
+    }
+
+    /// takes over the servers of `input`, new ones are due right away
+    pub fn reload(&mut self, input: &UserInput) {
+        let now = Instant::now();
+        self.adaptive = input.adaptive_periods();
+        self.jitter = input.jitter;
+        self.fixed = input
+            .servers
+            .iter()
+            .filter(|s| s.period.is_some())
+            .map(|s| s.name.clone())
+            .collect();
+        self.periods = input
+            .servers
+            .iter()
+            .map(|s| (s.name.clone(), input.period_of(s)))
+            .collect();
+        self.apply_adaptive();
+        let periods = &self.periods;
+        self.next_poll.retain(|s, _| periods.contains_key(s));
+        for server in self.periods.keys() {
+            self.next_poll.entry(server.clone()).or_insert(now);
+        }
+    }
+
+    pub fn due_servers(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.next_poll
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(s, _)| s.clone())
+            .collect()
+    }
+
+    /// switches servers without a period of their own to the active or idle period, the poll
+    /// already scheduled is brought forward when sessions just opened
+    pub fn adapt(&mut self, busy: bool) {
+        let adaptive = match self.adaptive {
+            Some(adaptive) if busy != self.busy => adaptive,
+            _ => return,
+        };
+        self.busy = busy;
+        if busy {
+            info!("sessions are open, polling every {:?}", adaptive.active);
+        } else {
+            info!("no sessions are open, polling every {:?}", adaptive.idle);
+        }
+        self.apply_adaptive();
+        if busy {
+            let soonest = Instant::now() + adaptive.active;
+            for (server, at) in &mut self.next_poll {
+                if !self.fixed.contains(server) && *at > soonest {
+                    *at = soonest;
+                }
+            }
+        }
+    }
+
+    fn apply_adaptive(&mut self) {
+        if let Some(adaptive) = self.adaptive {
+            let period = if self.busy {
+                adaptive.active
+            } else {
+                adaptive.idle
+            };
+            for (server, p) in &mut self.periods {
+                if !self.fixed.contains(server) {
+                    *p = period;
+                }
+            }
+        }
+    }
+
+    pub fn polled(&mut self, servers: &[String]) {
+        let now = Instant::now();
+        for server in servers {
+            if let Some(period) = self.periods.get(server) {
+                self.next_poll
+                    .insert(server.clone(), now + jittered(*period, self.jitter));
+            }
+        }
+    }
+
+    /// the period the cycle polling `servers` should have been done within
+    pub fn shortest_period(&self, servers: &[String]) -> Option<Duration> {
+        servers
+            .iter()
+            .filter_map(|s| self.periods.get(s))
+            .min()
+            .copied()
+    }
+
+    pub fn next_wake(&self) -> Instant {
+        self.next_poll
+            .values()
+            .min()
+            .copied()
+            .unwrap_or_else(Instant::now)
+    }
+}
+
+/// `period` moved randomly by up to `jitter` percent either way
+fn jittered(period: Duration, jitter: u32) -> Duration {
+    if jitter == 0 {
+        return period;
+    }
+    let factor = 1.0 + rand::thread_rng().gen_range(-1.0..=1.0) * f64::from(jitter) / 100.0;
+    period.mul_f64(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn adaptive_polling_leaves_own_periods_alone() {
+        let secs = Duration::from_secs;
+        let input = UserInput {
+            servers: vec![
+                ServerConfig {
+                    name: "SERVER01".to_owned(),
+                    period: None,
+                    url: None,
+                },
+                ServerConfig {
+                    name: "SERVER02".to_owned(),
+                    period: Some(secs(60)),
+                    url: None,
+                },
+            ],
+            active_period: Some(secs(5)),
+            idle_period: Some(secs(300)),
+            ..UserInput::default()
+        };
+        let mut schedule = PollSchedule::new(&input);
+        let period = |schedule: &PollSchedule, server: &str| {
+            schedule.shortest_period(&[server.to_owned()]).unwrap()
+        };
+        assert_eq!(period(&schedule, "SERVER01"), secs(300));
+        schedule.adapt(true);
+        assert_eq!(period(&schedule, "SERVER01"), secs(5));
+        assert_eq!(period(&schedule, "SERVER02"), secs(60));
+        schedule.adapt(false);
+        assert_eq!(period(&schedule, "SERVER01"), secs(300));
+    }
+}
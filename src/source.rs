@@ -0,0 +1,91 @@
+use anyhow::Result;
+use rdc_connections::{RemoteDesktopSessionInfo, RemoteServer};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const AF_INET: u32 = 2;
+const AF_INET6: u32 = 23;
+
+/// anything sessions can be queried from, a windows server in production
+pub trait SessionSource {
+    fn name(&self) -> &str;
+    fn get_updated_info(&mut self) -> Result<Vec<RemoteDesktopSessionInfo>>;
+}
+
+/// a windows server, opened on the first query and again after a failed one, as opening it
+/// does not tell whether the server can be reached
+pub struct WtsServer {
+    name: String,
+    server: Option<RemoteServer>,
+}
+
+impl WtsServer {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            server: None,
+        }
+    }
+}
+
+impl SessionSource for WtsServer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_updated_info(&mut self) -> Result<Vec<RemoteDesktopSessionInfo>> {
+        let server = match &mut self.server {
+            Some(server) => server,
+            None => self.server.insert(RemoteServer::new(self.name.as_str())?),
+        };
+        let result = server.get_updated_info();
+        if result.is_err() {
+            self.server = None;
+        }
+        result
+    }
+}
+
+/// `WTSCLIENT` address of a session, which holds one byte of the address per element
+pub fn client_address(address: &(u32, [u16; 31])) -> Option<IpAddr> {
+    let (family, bytes) = address;
+    match *family {
+        AF_INET => Some(IpAddr::V4(Ipv4Addr::new(
+            bytes[0] as u8,
+            bytes[1] as u8,
+            bytes[2] as u8,
+            bytes[3] as u8,
+        ))),
+        AF_INET6 => {
+            let mut octets = [0_u8; 16];
+            octets
+                .iter_mut()
+                .zip(bytes.iter())
+                .for_each(|(o, b)| *o = *b as u8);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+/// sam account name of `DOMAIN\user` and `user@domain.com` alike, local accounts have no
+/// domain and are kept as they are
+pub fn bare_user(user: &str) -> &str {
+    match (user.rsplit_once('\\'), user.split_once('@')) {
+        (Some((_, name)), _) => name,
+        (None, Some((name, _))) if !name.is_empty() => name,
+        _ => user,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domains_are_stripped_from_users() {
+        assert_eq!(bare_user("CORP\\alice"), "alice");
+        assert_eq!(bare_user("alice@corp.example.com"), "alice");
+        assert_eq!(bare_user(".\\admin"), "admin");
+        assert_eq!(bare_user("alice"), "alice");
+    }
+}